@@ -1,312 +1,2380 @@
 use akula::{
     accessors::{chain, state},
-    consensus::engine_factory,
+    consensus::{engine_factory, FinalizationChange},
+    crypto::keccak256,
     execution::{
-        analysis_cache::AnalysisCache, evmglue, processor::ExecutionProcessor, tracer::NoopTracer,
+        evm::StatusCode,
+        evmglue,
+        processor::ExecutionProcessor,
+        tracer::{NoopTracer, Tracer},
+    },
+    kv::{
+        mdbx::*,
+        tables::{self, BitmapKey},
+        traits::{Table, TableDecode},
+        MdbxWithDirHandle,
     },
-    kv::{mdbx::*, tables, MdbxWithDirHandle},
     models::*,
     rpc::helpers,
     stagedsync::stages::FINISH,
+    trie::unmarshal_node,
     Buffer, IntraBlockState,
 };
 use anyhow::format_err;
 use ethereum_jsonrpc::types;
-use std::sync::Arc;
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    hash::Hash,
+    ops::RangeInclusive,
+    sync::Arc,
+};
 
-#[derive(Debug)]
-pub struct DbWrapper<DB>
-where
-    DB: EnvironmentKind,
-{
-    db: Arc<MdbxWithDirHandle<DB>>,
-    call_gas_limit: u64,
+use crate::{
+    executor::{
+        self, AnalysisCacheStats, ExecutionResult, InvalidTransaction, SharedAnalysisCache,
+    },
+    pool::Cancellation,
+    proof::{self, Overlaid, ProofBuilder, StoredBranch},
+    tracer::{
+        self, AccessListTracer, MultiTracer, ParityTracer, SharedTracer, StateDiffTracer,
+        StructLogger, StructLoggerConfig, VmTracer,
+    },
+    utils,
+};
+
+/// Maximum number of blocks a single fee history query may span.
+const MAX_FEE_HISTORY_BLOCKS: u64 = 1024;
+
+/// Maximum number of blocks a single log query may span.
+const MAX_LOG_QUERY_BLOCKS: u64 = 10_000;
+
+/// Maximum number of accounts changed after a block that a proof at it may roll back.
+const MAX_PROOF_CHANGED_ACCOUNTS: usize = 1_000_000;
+
+const ELASTICITY_MULTIPLIER: u64 = 2;
+const BASE_FEE_MAX_CHANGE_DENOMINATOR: u64 = 8;
+const INITIAL_BASE_FEE: u64 = 1_000_000_000;
+
+/// Contracts whose jump destination analysis each pooled cache keeps.
+const ANALYSIS_CACHE_SIZE: usize = 5_000;
+
+/// Priority fee suggested when the sampled blocks contain no transactions.
+const DEFAULT_PRIORITY_FEE: u64 = 1_000_000_000;
+
+/// Blocks to search in a log query.
+#[derive(Clone, Copy, Debug)]
+pub enum LogFilterBlocks {
+    Range {
+        from: types::BlockNumber,
+        to: types::BlockNumber,
+    },
+    Hash(H256),
 }
-impl<DB: EnvironmentKind> DbWrapper<DB> {
-    pub fn new(db: Arc<MdbxWithDirHandle<DB>>, call_gas_limit: u64) -> Self {
-        Self { db, call_gas_limit }
+
+/// Address and topic criteria of a log query. `None` matches anything, a set
+/// matches if any of its entries does.
+#[derive(Clone, Debug)]
+pub struct LogFilter {
+    pub blocks: LogFilterBlocks,
+    pub addresses: Option<Vec<Address>>,
+    pub topics: [Option<Vec<H256>>; 4],
+}
+
+impl LogFilter {
+    fn may_match_bloom(&self, bloom: &Bloom) -> bool {
+        let address_matches = self.addresses.as_ref().map_or(true, |addresses| {
+            addresses
+                .iter()
+                .any(|address| bloom_contains(bloom, address.as_bytes()))
+        });
+
+        address_matches
+            && self.topics.iter().flatten().all(|topics| {
+                topics
+                    .iter()
+                    .any(|topic| bloom_contains(bloom, topic.as_bytes()))
+            })
+    }
+
+    fn matches(&self, log: &Log) -> bool {
+        let address_matches = self
+            .addresses
+            .as_ref()
+            .map_or(true, |addresses| addresses.contains(&log.address));
+
+        address_matches
+            && self
+                .topics
+                .iter()
+                .enumerate()
+                .all(|(i, topics)| match topics {
+                    Some(topics) => log
+                        .topics
+                        .get(i)
+                        .map_or(false, |topic| topics.contains(topic)),
+                    None => true,
+                })
     }
 }
 
-impl<DB> DbWrapper<DB>
-where
-    DB: EnvironmentKind,
-{
-    pub async fn block_number(&self) -> anyhow::Result<U64> {
-        Ok(U64::from(
-            self.db
-                .begin()?
-                .get(tables::SyncStage, FINISH)?
-                .unwrap_or(BlockNumber(0))
-                .0,
-        ))
+/// Byte index and mask of the three bloom bits set by `input`.
+fn bloom_bits(input: &[u8]) -> impl Iterator<Item = (usize, u8)> {
+    let hash = keccak256(input);
+    (0..3).map(move |i| {
+        let bit = ((usize::from(hash[2 * i]) << 8) | usize::from(hash[2 * i + 1])) & 2047;
+        (Bloom::len_bytes() - 1 - bit / 8, 1 << (bit % 8))
+    })
+}
+
+fn bloom_contains(bloom: &Bloom, input: &[u8]) -> bool {
+    let bloom = bloom.as_bytes();
+    bloom_bits(input).all(|(byte, mask)| bloom[byte] & mask != 0)
+}
+
+fn logs_bloom(logs: &[Log]) -> Bloom {
+    let mut bloom = Bloom::zero();
+    let bytes = bloom.as_bytes_mut();
+    for log in logs {
+        for input in std::iter::once(log.address.as_bytes())
+            .chain(log.topics.iter().map(|topic| topic.as_bytes()))
+        {
+            for (byte, mask) in bloom_bits(input) {
+                bytes[byte] |= mask;
+            }
+        }
     }
+    bloom
+}
 
-    pub async fn call(
-        &self,
-        call_data: types::MessageCall,
-        block_id: types::BlockId,
-    ) -> anyhow::Result<types::Bytes> {
-        let txn = self.db.begin()?;
+/// Fee market data of a range of blocks, as returned by `eth_feeHistory`.
+#[derive(Clone, Debug)]
+pub struct FeeHistory {
+    pub oldest_block: BlockNumber,
+    /// Base fee of every block in the range plus the one of the block that follows it.
+    pub base_fee_per_gas: Vec<U256>,
+    pub gas_used_ratio: Vec<f64>,
+    /// Effective priority fees at the requested percentiles, weighted by gas used.
+    pub reward: Vec<Vec<U256>>,
+}
 
-        let (block_number, block_hash) = helpers::resolve_block_id(&txn, block_id)?
-            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
-        let chain_id = txn
-            .get(tables::Config, ())?
-            .ok_or_else(|| format_err!("chain spec not found"))?
-            .params
-            .chain_id;
+/// Fees suggested for a transaction to be included in the next block.
+#[derive(Clone, Copy, Debug)]
+pub struct FeeSuggestion {
+    pub base_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+}
 
-        let header = chain::header::read(&txn, block_hash, block_number)?
-            .ok_or_else(|| format_err!("Header not found for #{block_number}/{block_hash}"))?
-            .into();
+/// Account state at a block together with its EIP-1186 Merkle proofs.
+#[derive(Clone, Debug)]
+pub struct AccountProof {
+    pub address: Address,
+    pub balance: U256,
+    pub nonce: u64,
+    pub code_hash: H256,
+    pub storage_hash: H256,
+    pub account_proof: Vec<Bytes>,
+    pub storage_proof: Vec<StorageProof>,
+}
 
-        let mut buffer = Buffer::new(&txn, Some(block_number));
+#[derive(Clone, Debug)]
+pub struct StorageProof {
+    pub key: H256,
+    pub value: U256,
+    pub proof: Vec<Bytes>,
+}
 
-        let (sender, message) = helpers::convert_message_call(
-            &buffer,
-            chain_id,
-            call_data,
-            &header,
-            U256::ZERO,
-            Some(self.call_gas_limit),
-        )?;
+/// Which traces to replay transactions with, as in `trace_replayBlockTransactions`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TraceTypes {
+    pub trace: bool,
+    pub vm_trace: bool,
+    pub state_diff: bool,
+}
 
-        let mut state = IntraBlockState::new(&mut buffer);
+/// Balance, nonce and code of an existing account.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccountState {
+    pub balance: U256,
+    pub nonce: u64,
+    pub code: Bytes,
+}
 
-        let mut analysis_cache = AnalysisCache::default();
-        let block_spec = chain::chain_config::read(&txn)?
-            .ok_or_else(|| format_err!("no chainspec found"))?
-            .collect_block_spec(block_number);
+/// State of an account before and after a change, `None` meaning it does not exist.
+/// Storage maps slots to their values before and after.
+#[derive(Clone, Debug)]
+pub struct AccountChange {
+    pub before: Option<AccountState>,
+    pub after: Option<AccountState>,
+    pub storage: BTreeMap<H256, (U256, U256)>,
+}
 
-        let mut tracer = NoopTracer;
+/// Page of an account's non-zero storage slots, ordered by slot. `next_key` is the
+/// slot the next page starts at, if any.
+#[derive(Clone, Debug, Default)]
+pub struct StorageRange {
+    pub storage: BTreeMap<H256, U256>,
+    pub next_key: Option<H256>,
+}
 
-        Ok(evmglue::execute(
-            &mut state,
-            &mut tracer,
-            &mut analysis_cache,
-            &header,
-            &block_spec,
-            &message,
-            sender,
-            message.gas_limit(),
-        )?
-        .output_data
-        .into())
+/// Account at a block as dumped by `dump_state`.
+#[derive(Clone, Debug)]
+pub struct DumpAccount {
+    pub address: Address,
+    pub balance: U256,
+    pub nonce: u64,
+    pub code_hash: H256,
+    pub code: Bytes,
+    /// Non-zero storage slots, if requested.
+    pub storage: Option<BTreeMap<H256, U256>>,
+}
+
+/// Replacement state of an account for a simulated call, as in geth's state overrides.
+/// `state` replaces the whole storage while `state_diff` patches individual slots.
+#[derive(Clone, Debug, Default)]
+pub struct AccountOverride {
+    pub balance: Option<U256>,
+    pub nonce: Option<u64>,
+    pub code: Option<Bytes>,
+    pub state: Option<HashMap<H256, U256>>,
+    pub state_diff: Option<HashMap<H256, U256>>,
+}
+
+impl AccountOverride {
+    /// Applies `other` on top of this override.
+    pub fn merge(&mut self, other: AccountOverride) {
+        if other.balance.is_some() {
+            self.balance = other.balance;
+        }
+        if other.nonce.is_some() {
+            self.nonce = other.nonce;
+        }
+        if other.code.is_some() {
+            self.code = other.code;
+        }
+        if other.state.is_some() {
+            self.state = other.state;
+            self.state_diff = None;
+        }
+        if let Some(state_diff) = other.state_diff {
+            match &mut self.state {
+                Some(state) => state.extend(state_diff),
+                None => self
+                    .state_diff
+                    .get_or_insert_with(HashMap::new)
+                    .extend(state_diff),
+            }
+        }
     }
 
-    pub async fn estimate_gas(
-        &self,
-        call_data: types::MessageCall,
-        block_number: types::BlockNumber,
-    ) -> anyhow::Result<U64> {
-        let txn = self.db.begin()?;
-        let (block_number, hash) = helpers::resolve_block_id(&txn, block_number)?
-            .ok_or_else(|| format_err!("failed to resolve block {block_number:?}"))?;
+    /// Value of a storage slot if the override determines it.
+    pub fn storage(&self, location: &H256) -> Option<U256> {
+        if let Some(value) = self
+            .state_diff
+            .as_ref()
+            .and_then(|state_diff| state_diff.get(location))
+        {
+            return Some(*value);
+        }
+        self.state
+            .as_ref()
+            .map(|state| state.get(location).copied().unwrap_or(U256::ZERO))
+    }
+}
 
-        let chain_id = txn
-            .get(tables::Config, ())?
-            .ok_or_else(|| format_err!("chain spec not found"))?
-            .params
-            .chain_id;
-        let header = chain::header::read(&txn, hash, block_number)?
-            .ok_or_else(|| format_err!("no header found for block #{block_number}/{hash}"))?
-            .into();
-        let mut buffer = Buffer::new(&txn, Some(block_number));
+pub type StateOverride = HashMap<Address, AccountOverride>;
 
-        let (sender, message) =
-            helpers::convert_message_call(&buffer, chain_id, call_data, &header, U256::ZERO, None)?;
+/// Applies the overrides of `other` on top of `state_override`.
+pub fn merge_state_override(state_override: &mut StateOverride, other: StateOverride) {
+    for (address, account_override) in other {
+        state_override
+            .entry(address)
+            .or_default()
+            .merge(account_override);
+    }
+}
 
-        let mut state = IntraBlockState::new(&mut buffer);
+/// Persistent state overlay on top of a block that locally sent transactions execute on.
+/// It owns everything the transactions changed; whatever it doesn't hold is read from
+/// the block, so it keeps no database transaction open between operations.
+///
+/// Sent transactions are not signed, so they are recorded under
+/// `keccak256(sender ++ nonce)`, with the nonce big-endian, instead of their hash.
+#[derive(Debug, Default)]
+pub struct Sandbox {
+    /// Block the overlay sits on, pinned to the latest block on first use if not set.
+    base: Option<BlockNumber>,
+    /// Whether the transactions must fit in one block's gas limit, as a pending block's do.
+    bounded: bool,
+    state: StateOverride,
+    transactions: HashMap<H256, types::Transaction>,
+    receipts: HashMap<H256, types::TransactionReceipt>,
+    cumulative_gas_used: u64,
+    log_count: usize,
+}
 
-        let mut cache = AnalysisCache::default();
-        let block_spec = chain::chain_config::read(&txn)?
-            .ok_or_else(|| format_err!("no chainspec found"))?
-            .collect_block_spec(block_number);
-        let mut tracer = NoopTracer;
-        let gas_limit = header.gas_limit;
+impl Sandbox {
+    pub fn new(base: Option<BlockNumber>) -> Self {
+        Self {
+            base,
+            ..Default::default()
+        }
+    }
 
-        Ok(U64::from(
-            gas_limit as i64
-                - evmglue::execute(
-                    &mut state,
-                    &mut tracer,
-                    &mut cache,
-                    &header,
-                    &block_spec,
-                    &message,
-                    sender,
-                    gas_limit,
-                )?
-                .gas_left,
-        ))
+    /// A sandbox for the block following `parent`, which only includes transactions while
+    /// the block has gas left for them.
+    pub fn pending_block(parent: BlockNumber) -> Self {
+        Self {
+            base: Some(parent),
+            bounded: true,
+            ..Default::default()
+        }
     }
 
-    pub async fn get_balance(
-        &self,
-        address: Address,
-        block_id: types::BlockId,
-    ) -> anyhow::Result<U256> {
-        let txn = self.db.begin()?;
+    pub fn state(&self) -> &StateOverride {
+        &self.state
+    }
 
-        let (block_number, _) = helpers::resolve_block_id(&txn, block_id)?
-            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
-        Ok(state::account::read(&txn, address, Some(block_number))?
-            .map(|acc| acc.balance)
-            .unwrap_or(U256::ZERO))
+    pub fn transaction(&self, hash: &H256) -> Option<&types::Transaction> {
+        self.transactions.get(hash)
     }
 
-    pub async fn get_block(
-        &self,
-        block_id: types::BlockId,
-        include_txs: bool,
-    ) -> anyhow::Result<Option<types::Block>> {
-        Ok(helpers::construct_block(
-            &self.db.begin()?,
-            block_id,
-            include_txs,
-            None,
-        )?)
+    pub fn receipt(&self, hash: &H256) -> Option<&types::TransactionReceipt> {
+        self.receipts.get(hash)
     }
 
-    pub async fn get_transaction_by_hash(
-        &self,
+    /// Whether a transaction of `gas_limit` still fits in a block of `block_gas_limit`.
+    fn fits(&self, block_gas_limit: u64, gas_limit: u64) -> bool {
+        !self.bounded || gas_limit <= block_gas_limit.saturating_sub(self.cumulative_gas_used)
+    }
+
+    /// Folds the accounts a transaction left as `changes` into the overlay and records the
+    /// transaction and its receipt under `hash`.
+    #[allow(clippy::too_many_arguments)]
+    fn record(
+        &mut self,
         hash: H256,
-    ) -> anyhow::Result<Option<types::Transaction>> {
-        let txn = self.db.begin()?;
-        if let Some(block_number) = chain::tl::read(&txn, hash)? {
-            let block_hash = chain::canonical_hash::read(&txn, block_number)?
-                .ok_or_else(|| format_err!("canonical hash for block #{block_number} not found"))?;
-            let (index, transaction) = chain::block_body::read_without_senders(
-                                &txn,
-                                block_hash,
-                                block_number,
-                            )?.ok_or_else(|| format_err!("body not found for block #{block_number}/{block_hash}"))?
-                        .transactions
-                        .into_iter()
-                        .enumerate()
-                        .find(|(_, tx)| tx.hash() == hash)
-                        .ok_or_else(|| {
-                                    format_err!(
-                                            "tx with hash {hash} is not found in block #{block_number}/{block_hash} - tx lookup index invalid?"
-                                        )
-                                })?;
-            let senders = chain::tx_sender::read(&txn, block_hash, block_number)?;
-            let sender = *senders
-                .get(index)
-                .ok_or_else(|| format_err!("senders to short: {index} vs len {}", senders.len()))?;
-            return Ok(Some(types::Transaction {
+        block_number: BlockNumber,
+        sender: Address,
+        nonce: u64,
+        message: &Message,
+        result: &ExecutionResult,
+        changes: StateOverride,
+    ) {
+        merge_state_override(&mut self.state, changes);
+
+        self.cumulative_gas_used += result.gas_used;
+        let transaction_index = U64::from(self.receipts.len());
+        let logs = result
+            .logs
+            .iter()
+            .enumerate()
+            .map(|(i, log)| types::TransactionLog {
+                log_index: Some(U64::from(self.log_count + i)),
+                transaction_index: Some(transaction_index),
+                transaction_hash: Some(hash),
+                block_hash: None,
+                block_number: Some(U64::from(block_number.0)),
+                address: log.address,
+                data: log.data.clone().into(),
+                topics: log.topics.clone(),
+            })
+            .collect::<Vec<_>>();
+        self.log_count += logs.len();
+
+        self.transactions.insert(
+            hash,
+            types::Transaction {
                 hash,
-                nonce: transaction.nonce().into(),
-                block_hash: Some(block_hash),
+                nonce: nonce.into(),
+                block_hash: None,
                 block_number: Some(block_number.0.into()),
                 from: sender,
-                gas: transaction.gas_limit().into(),
-                gas_price: match transaction.message {
-                    Message::Legacy { gas_price, .. } => gas_price,
-                    Message::EIP2930 { gas_price, .. } => gas_price,
+                gas: message.gas_limit().into(),
+                gas_price: match message {
+                    Message::Legacy { gas_price, .. } => *gas_price,
+                    Message::EIP2930 { gas_price, .. } => *gas_price,
                     Message::EIP1559 {
                         max_fee_per_gas, ..
-                    } => max_fee_per_gas,
+                    } => *max_fee_per_gas,
                 },
-                input: transaction.input().clone().into(),
-                to: match transaction.action() {
-                    TransactionAction::Call(to) => Some(to),
-                    TransactionAction::Create => None,
+                input: message.input().clone().into(),
+                to: message.action().into_address(),
+                transaction_index: Some(transaction_index),
+                value: message.value(),
+                v: U64::zero(),
+                r: H256::zero(),
+                s: H256::zero(),
+            },
+        );
+        self.receipts.insert(
+            hash,
+            types::TransactionReceipt {
+                transaction_hash: hash,
+                transaction_index,
+                block_hash: H256::zero(),
+                block_number: U64::from(block_number.0),
+                from: sender,
+                to: message.action().into_address(),
+                cumulative_gas_used: self.cumulative_gas_used.into(),
+                gas_used: result.gas_used.into(),
+                contract_address: if let TransactionAction::Create = message.action() {
+                    Some(akula::execution::address::create_address(sender, nonce))
+                } else {
+                    None
+                },
+                logs,
+                logs_bloom: logs_bloom(&result.logs),
+                status: if result.status_code == StatusCode::Success {
+                    U64::from(1_u16)
+                } else {
+                    U64::zero()
                 },
-                transaction_index: Some(U64::from(index)),
-                value: transaction.value(),
-                v: transaction.v().into(),
-                r: transaction.r(),
-                s: transaction.s(),
-            }));
-        }
-
-        Ok(None)
-    }
-
-    pub async fn get_code(
-        &self,
-        address: Address,
-        block_id: types::BlockId,
-    ) -> anyhow::Result<types::Bytes> {
-        let txn = self.db.begin()?;
-        let (block_number, _) = helpers::resolve_block_id(&txn, block_id)?
-            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
-        Ok(
-            if let Some(account) = state::account::read(&txn, address, Some(block_number))? {
-                txn.get(tables::Code, account.code_hash)?
-                    .ok_or_else(|| {
-                        format_err!("failed to find code for code hash {}", account.code_hash)
-                    })?
-                    .into()
-            } else {
-                Default::default()
             },
-        )
+        );
     }
+}
 
-    pub async fn get_storage_at(
-        &self,
-        address: Address,
-        key: U256,
-        block_id: types::BlockId,
-    ) -> anyhow::Result<U256> {
-        let txn = self.db.begin()?;
-        let (block_number, _) = helpers::resolve_block_id(&txn, block_id)?
-            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
-        Ok(state::storage::read(
-            &txn,
-            address,
-            key,
-            Some(block_number),
-        )?)
+/// Header fields to replace when simulating a call "as if" in another block. The state
+/// is still the one of the block the call runs on.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BlockOverride {
+    pub number: Option<BlockNumber>,
+    pub timestamp: Option<u64>,
+    pub base_fee_per_gas: Option<U256>,
+    pub coinbase: Option<Address>,
+    pub gas_limit: Option<u64>,
+    pub difficulty: Option<U256>,
+    /// Replaces the mix hash, which `DIFFICULTY` returns after the merge.
+    pub prevrandao: Option<H256>,
+}
+
+impl BlockOverride {
+    fn apply(&self, header: &mut PartialHeader) {
+        if let Some(number) = self.number {
+            header.number = number;
+        }
+        if let Some(timestamp) = self.timestamp {
+            header.timestamp = timestamp;
+        }
+        if let Some(base_fee_per_gas) = self.base_fee_per_gas {
+            header.base_fee_per_gas = Some(base_fee_per_gas);
+        }
+        if let Some(coinbase) = self.coinbase {
+            header.beneficiary = coinbase;
+        }
+        if let Some(gas_limit) = self.gas_limit {
+            header.gas_limit = gas_limit;
+        }
+        if let Some(difficulty) = self.difficulty {
+            header.difficulty = difficulty;
+        }
+        if let Some(prevrandao) = self.prevrandao {
+            header.mix_hash = prevrandao;
+        }
     }
+}
 
-    pub async fn get_transaction_count(
-        &self,
-        address: Address,
-        block_id: types::BlockId,
-    ) -> anyhow::Result<U64> {
-        let txn = self.db.begin()?;
-        let (block_number, _) = helpers::resolve_block_id(&txn, block_id)?
-            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+/// Outcome of one transaction of a simulated bundle.
+#[derive(Clone, Debug)]
+pub struct BundleTransactionResult {
+    pub status_code: StatusCode,
+    pub output: Bytes,
+    pub gas_used: u64,
+    pub logs: Vec<Log>,
+}
 
-        Ok(state::account::read(&txn, address, Some(block_number))?
-            .map(|account| account.nonce)
+/// Block a batch of calls runs on, resolved once for every call targeting it.
+#[derive(Clone, Debug)]
+pub struct CallBlock {
+    number: BlockNumber,
+    header: PartialHeader,
+}
+
+/// Result of a gas estimation.
+#[derive(Clone, Debug)]
+pub enum GasEstimate {
+    Gas(u64),
+    /// The call fails even with the highest gas limit it may use.
+    Failed {
+        status_code: StatusCode,
+        output: Bytes,
+    },
+}
+
+/// Everything needed to execute a message call on top of a block.
+struct CallEnv {
+    block_number: BlockNumber,
+    header: PartialHeader,
+    block_spec: BlockExecutionSpec,
+    chain_id: ChainId,
+    sender: Address,
+    message: Message,
+}
+
+/// Entry of a change set, holding the value prior to the change set's block.
+enum Change {
+    Account {
+        address: Address,
+        account: Option<Account>,
+    },
+    Storage {
+        address: Address,
+        location: H256,
+        value: U256,
+    },
+}
+
+/// Values of the accounts and slots changed in a range of blocks as of the block before
+/// it, which is the first change-set entry of each.
+#[derive(Debug, Default)]
+struct PriorState {
+    accounts: BTreeMap<Address, Option<Account>>,
+    storage: BTreeMap<Address, BTreeMap<H256, U256>>,
+}
+
+/// Net change of an account over a range of blocks: the account before and after, if it
+/// differs, and the slots whose values differ.
+#[derive(Debug, Default)]
+struct NetChange {
+    account: Option<(Option<Account>, Option<Account>)>,
+    storage: BTreeMap<H256, (U256, U256)>,
+}
+
+impl PriorState {
+    /// Records a change visited in block order, keeping the first one of each entry.
+    fn record(&mut self, change: Change) {
+        match change {
+            Change::Account { address, account } => {
+                self.accounts.entry(address).or_insert(account);
+            }
+            Change::Storage {
+                address,
+                location,
+                value,
+            } => {
+                self.storage
+                    .entry(address)
+                    .or_default()
+                    .entry(location)
+                    .or_insert(value);
+            }
+        }
+    }
+
+    /// Pairs the prior values with the values after the range, read with `account` and
+    /// `storage`, and keeps the ones that differ.
+    fn net_changes(
+        self,
+        mut account: impl FnMut(Address) -> anyhow::Result<Option<Account>>,
+        mut storage: impl FnMut(Address, H256) -> anyhow::Result<U256>,
+    ) -> anyhow::Result<BTreeMap<Address, NetChange>> {
+        let mut changes = BTreeMap::<Address, NetChange>::new();
+        for (address, before) in self.accounts {
+            let after = account(address)?;
+            if before != after {
+                changes.entry(address).or_default().account = Some((before, after));
+            }
+        }
+        for (address, slots) in self.storage {
+            for (location, before) in slots {
+                let after = storage(address, location)?;
+                if before != after {
+                    changes
+                        .entry(address)
+                        .or_default()
+                        .storage
+                        .insert(location, (before, after));
+                }
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// Account trie at a historical block: the head's stored branch nodes and hashed
+/// accounts, with the accounts changed after the block rolled back.
+struct AccountTrie<'a, 'tx, K, DB>
+where
+    K: TransactionKind,
+    DB: EnvironmentKind,
+{
+    txn: &'a MdbxTransaction<'tx, K, DB>,
+    block_number: BlockNumber,
+    /// Accounts changed after the block, by hashed address.
+    changed: &'a BTreeMap<H256, Address>,
+    /// Hashed address and storage root of the account being proven.
+    target: (H256, H256),
+}
+
+impl<'a, 'tx, K, DB> proof::TrieSource for AccountTrie<'a, 'tx, K, DB>
+where
+    K: TransactionKind,
+    DB: EnvironmentKind,
+{
+    fn branch(&mut self, prefix: &[u8]) -> anyhow::Result<Option<StoredBranch>> {
+        self.txn
+            .get(tables::TrieAccount, prefix.to_vec())?
+            .map(|node| stored_branch(&node))
+            .transpose()
+    }
+
+    fn first_branch_below(&mut self, prefix: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut cursor = self.txn.cursor(tables::TrieAccount)?;
+        first_key_below(cursor.walk(Some(prefix.to_vec())), prefix)
+    }
+
+    fn changed(&self, prefix: &[u8]) -> bool {
+        let (low, high) = proof::prefix_bounds(prefix);
+        self.changed.range(low..=high).next().is_some()
+    }
+
+    fn leaves(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<(H256, Vec<u8>)>> {
+        let (low, high) = proof::prefix_bounds(prefix);
+        let mut cursor = self.txn.cursor(tables::HashedAccount)?;
+        let base = cursor
+            .walk(Some(low))
+            .take_while(|entry| entry.as_ref().map_or(true, |(key, _)| *key <= high));
+        let overlay = self
+            .changed
+            .range(low..=high)
+            .map(|(hashed_address, address)| {
+                Ok((
+                    *hashed_address,
+                    state::account::read(self.txn, *address, Some(self.block_number))?,
+                ))
+            });
+
+        Overlaid::new(base, overlay)
+            .map(|entry| {
+                let (hashed_address, account) = entry?;
+                let storage_root = if hashed_address == self.target.0 {
+                    self.target.1
+                } else {
+                    let changes = match self.changed.get(&hashed_address) {
+                        Some(address) => DbWrapper::<DB>::hashed_storage_changes(
+                            self.txn,
+                            *address,
+                            self.block_number,
+                        )?,
+                        None => BTreeMap::new(),
+                    };
+                    DbWrapper::<DB>::storage_trie(self.txn, hashed_address, changes, &[])?.root
+                };
+                Ok((hashed_address, proof::account_leaf(&account, storage_root)))
+            })
+            .collect()
+    }
+}
+
+/// Storage trie of an account at a historical block: the head's stored branch nodes and
+/// hashed storage, with `changes` holding the slots' values at the block.
+struct StorageTrie<'a, 'tx, K, DB>
+where
+    K: TransactionKind,
+    DB: EnvironmentKind,
+{
+    txn: &'a MdbxTransaction<'tx, K, DB>,
+    hashed_address: H256,
+    changes: BTreeMap<H256, Option<U256>>,
+}
+
+impl<'a, 'tx, K, DB> StorageTrie<'a, 'tx, K, DB>
+where
+    K: TransactionKind,
+    DB: EnvironmentKind,
+{
+    /// Storage trie nodes are keyed by the hashed address followed by their path.
+    fn node_key(&self, prefix: &[u8]) -> Vec<u8> {
+        [self.hashed_address.as_bytes(), prefix].concat()
+    }
+}
+
+impl<'a, 'tx, K, DB> proof::TrieSource for StorageTrie<'a, 'tx, K, DB>
+where
+    K: TransactionKind,
+    DB: EnvironmentKind,
+{
+    fn branch(&mut self, prefix: &[u8]) -> anyhow::Result<Option<StoredBranch>> {
+        self.txn
+            .get(tables::TrieStorage, self.node_key(prefix))?
+            .map(|node| stored_branch(&node))
+            .transpose()
+    }
+
+    fn first_branch_below(&mut self, prefix: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let key = self.node_key(prefix);
+        let mut cursor = self.txn.cursor(tables::TrieStorage)?;
+        Ok(first_key_below(cursor.walk(Some(key.clone())), &key)?
+            .map(|key| key[H256::len_bytes()..].to_vec()))
+    }
+
+    fn changed(&self, prefix: &[u8]) -> bool {
+        let (low, high) = proof::prefix_bounds(prefix);
+        self.changes.range(low..=high).next().is_some()
+    }
+
+    fn leaves(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<(H256, Vec<u8>)>> {
+        let (low, high) = proof::prefix_bounds(prefix);
+        let mut cursor = self.txn.cursor(tables::HashedStorage)?;
+        let base = cursor
+            .walk_dup(self.hashed_address, Some(low))
+            .take_while(|entry| entry.as_ref().map_or(true, |(key, _)| *key <= high));
+        let overlay = self
+            .changes
+            .range(low..=high)
+            .map(|(hashed_location, value)| Ok((*hashed_location, *value)));
+
+        Overlaid::new(base, overlay)
+            .map(|entry| {
+                let (hashed_location, value) = entry?;
+                Ok((hashed_location, proof::storage_leaf(value)))
+            })
+            .collect()
+    }
+}
+
+fn stored_branch(node: &[u8]) -> anyhow::Result<StoredBranch> {
+    let node = unmarshal_node(node).ok_or_else(|| format_err!("invalid trie node"))?;
+    Ok(StoredBranch {
+        state_mask: node.state_mask(),
+        hash_mask: node.hash_mask(),
+        hashes: node.hashes().to_vec(),
+    })
+}
+
+/// Resolves each distinct id of `ids` once with `resolve` and returns what every id
+/// resolved to, or an error for the ids `resolve` doesn't find.
+fn resolve_distinct<K, V>(
+    ids: impl IntoIterator<Item = K>,
+    mut resolve: impl FnMut(K) -> anyhow::Result<Option<V>>,
+) -> anyhow::Result<Vec<anyhow::Result<V>>>
+where
+    K: Copy + Eq + Hash + std::fmt::Debug,
+    V: Clone,
+{
+    let mut resolved = HashMap::new();
+    ids.into_iter()
+        .map(|id| {
+            let value = match resolved.entry(id) {
+                Entry::Occupied(entry) => entry.get().clone(),
+                Entry::Vacant(entry) => entry.insert(resolve(id)?).clone(),
+            };
+            Ok(value.ok_or_else(|| format_err!("block {id:?} not found")))
+        })
+        .collect()
+}
+
+/// The address following `address`, or `None` past the last one.
+fn next_address(address: Address) -> Option<Address> {
+    let mut bytes = address.0;
+    for byte in bytes.iter_mut().rev() {
+        let (next, overflow) = byte.overflowing_add(1);
+        *byte = next;
+        if !overflow {
+            return Some(Address::from(bytes));
+        }
+    }
+    None
+}
+
+/// Key of the first entry strictly below `prefix` in a walk of trie nodes starting at it.
+fn first_key_below<V>(
+    mut nodes: impl Iterator<Item = anyhow::Result<(Vec<u8>, V)>>,
+    prefix: &[u8],
+) -> anyhow::Result<Option<Vec<u8>>> {
+    nodes
+        .find_map(|entry| match entry {
+            Ok((key, _)) if key == prefix => None,
+            Ok((key, _)) => Some(Ok(key)),
+            Err(e) => Some(Err(e)),
+        })
+        .transpose()
+        .map(|key| key.filter(|key| key.starts_with(prefix)))
+}
+
+fn effective_priority_fee_per_gas(message: &Message, base_fee_per_gas: U256) -> U256 {
+    message
+        .max_priority_fee_per_gas()
+        .min(message.max_fee_per_gas().saturating_sub(base_fee_per_gas))
+}
+
+fn calculate_next_base_fee_per_gas(gas_used: u64, gas_limit: u64, base_fee_per_gas: U256) -> U256 {
+    let gas_target = gas_limit / ELASTICITY_MULTIPLIER;
+    if gas_target == 0 {
+        return base_fee_per_gas;
+    }
+
+    match gas_used.cmp(&gas_target) {
+        Ordering::Equal => base_fee_per_gas,
+        Ordering::Greater => {
+            let delta = base_fee_per_gas * U256::from(gas_used - gas_target)
+                / U256::from(gas_target)
+                / U256::from(BASE_FEE_MAX_CHANGE_DENOMINATOR);
+            base_fee_per_gas + delta.max(U256::ONE)
+        }
+        Ordering::Less => {
+            let delta = base_fee_per_gas * U256::from(gas_target - gas_used)
+                / U256::from(gas_target)
+                / U256::from(BASE_FEE_MAX_CHANGE_DENOMINATOR);
+            base_fee_per_gas.saturating_sub(delta)
+        }
+    }
+}
+
+/// Rejects reward percentiles outside of `0..=100` or not in ascending order, with geth's
+/// messages.
+fn validate_reward_percentiles(percentiles: &[f64]) -> anyhow::Result<()> {
+    for (i, percentile) in percentiles.iter().enumerate() {
+        if !(0.0..=100.0).contains(percentile) {
+            return Err(format_err!("invalid reward percentile: {percentile}"));
+        }
+        if i > 0 && *percentile < percentiles[i - 1] {
+            return Err(format_err!(
+                "invalid reward percentile: #{}:{} > #{i}:{percentile}",
+                i - 1,
+                percentiles[i - 1]
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Priority fees at the given percentiles of a block's gas used, from the `(fee, gas used)`
+/// pairs of its transactions. Same walk as geth: each percentile picks the fee of the
+/// transaction at which the cumulative gas used, in ascending fee order, reaches that share
+/// of the block's gas used.
+fn percentile_rewards(
+    mut fees: Vec<(U256, u64)>,
+    block_gas_used: u64,
+    percentiles: &[f64],
+) -> Vec<U256> {
+    if fees.is_empty() {
+        return vec![U256::ZERO; percentiles.len()];
+    }
+    fees.sort_unstable_by_key(|(fee, _)| *fee);
+
+    let mut reward = Vec::with_capacity(percentiles.len());
+    let mut index = 0;
+    let mut gas_used = fees[0].1;
+    for percentile in percentiles {
+        let threshold = (block_gas_used as f64 * percentile / 100.0) as u64;
+        while gas_used < threshold && index < fees.len() - 1 {
+            index += 1;
+            gas_used += fees[index].1;
+        }
+        reward.push(fees[index].0);
+    }
+    reward
+}
+
+#[derive(Debug)]
+pub struct DbWrapper<DB>
+where
+    DB: EnvironmentKind,
+{
+    db: Arc<MdbxWithDirHandle<DB>>,
+    call_gas_limit: u64,
+    analysis_cache: SharedAnalysisCache,
+}
+impl<DB: EnvironmentKind> DbWrapper<DB> {
+    pub fn new(db: Arc<MdbxWithDirHandle<DB>>, call_gas_limit: u64) -> Self {
+        Self::with_analysis_cache_size(db, call_gas_limit, ANALYSIS_CACHE_SIZE)
+    }
+
+    pub fn with_analysis_cache_size(
+        db: Arc<MdbxWithDirHandle<DB>>,
+        call_gas_limit: u64,
+        analysis_cache_size: usize,
+    ) -> Self {
+        Self {
+            db,
+            call_gas_limit,
+            analysis_cache: SharedAnalysisCache::new(analysis_cache_size),
+        }
+    }
+
+    pub fn analysis_cache_stats(&self) -> AnalysisCacheStats {
+        self.analysis_cache.stats()
+    }
+}
+
+impl<DB> DbWrapper<DB>
+where
+    DB: EnvironmentKind,
+{
+    pub fn block_number(&self) -> anyhow::Result<U64> {
+        Ok(U64::from(
+            self.db
+                .begin()?
+                .get(tables::SyncStage, FINISH)?
+                .unwrap_or(BlockNumber(0))
+                .0,
+        ))
+    }
+
+    pub fn chain_id(&self) -> anyhow::Result<U64> {
+        Ok(U64::from(
+            chain::chain_config::read(&self.db.begin()?)?
+                .ok_or_else(|| format_err!("chain specification not found"))?
+                .params
+                .chain_id
+                .0,
+        ))
+    }
+
+    pub fn net_version(&self) -> anyhow::Result<U64> {
+        Ok(U64::from(
+            chain::chain_config::read(&self.db.begin()?)?
+                .ok_or_else(|| format_err!("chain specification not found"))?
+                .params
+                .network_id
+                .0,
+        ))
+    }
+
+    pub fn call(
+        &self,
+        call_data: types::MessageCall,
+        block_id: types::BlockId,
+        state_override: &StateOverride,
+        block_override: &BlockOverride,
+    ) -> anyhow::Result<(StatusCode, types::Bytes)> {
+        let txn = self.db.begin()?;
+
+        let (block_number, block_hash) = helpers::resolve_block_id(&txn, block_id)?
+            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+        let chain_spec =
+            chain::chain_config::read(&txn)?.ok_or_else(|| format_err!("no chainspec found"))?;
+
+        let mut header: PartialHeader = chain::header::read(&txn, block_hash, block_number)?
+            .ok_or_else(|| format_err!("Header not found for #{block_number}/{block_hash}"))?
+            .into();
+        block_override.apply(&mut header);
+
+        self.execute_call(
+            &txn,
+            &chain_spec,
+            block_number,
+            &header,
+            call_data,
+            state_override,
+        )
+    }
+
+    /// Resolves the blocks of a batch of calls, each distinct block id once, and reads
+    /// the chain specification, all in one read transaction. Unknown blocks resolve to an
+    /// error for each call targeting them.
+    #[allow(clippy::type_complexity)]
+    pub fn resolve_call_blocks(
+        &self,
+        block_ids: Vec<types::BlockId>,
+    ) -> anyhow::Result<(Arc<ChainSpec>, Vec<anyhow::Result<Arc<CallBlock>>>)> {
+        let txn = self.db.begin()?;
+        let chain_spec =
+            chain::chain_config::read(&txn)?.ok_or_else(|| format_err!("no chainspec found"))?;
+
+        let blocks = resolve_distinct(block_ids, |block_id| {
+            Ok(match helpers::resolve_block_id(&txn, block_id)? {
+                Some((number, hash)) => chain::header::read(&txn, hash, number)?.map(|header| {
+                    Arc::new(CallBlock {
+                        number,
+                        header: header.into(),
+                    })
+                }),
+                None => None,
+            })
+        })?;
+
+        Ok((Arc::new(chain_spec), blocks))
+    }
+
+    /// Executes the calls on blocks from `resolve_call_blocks` one after another in one
+    /// read transaction, giving up between calls once `cancel` is set.
+    pub fn call_many(
+        &self,
+        chain_spec: &ChainSpec,
+        calls: Vec<(types::MessageCall, Arc<CallBlock>)>,
+        cancel: &Cancellation,
+    ) -> anyhow::Result<Vec<anyhow::Result<(StatusCode, types::Bytes)>>> {
+        let txn = self.db.begin()?;
+
+        calls
+            .into_iter()
+            .map(|(call_data, block)| {
+                cancel.check()?;
+                Ok(self.execute_call(
+                    &txn,
+                    chain_spec,
+                    block.number,
+                    &block.header,
+                    call_data,
+                    &StateOverride::new(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Returns the access list of every account and slot the call touches, along with the
+    /// gas the call uses once that list is attached to it.
+    pub fn create_access_list(
+        &self,
+        call_data: types::MessageCall,
+        block_id: types::BlockId,
+    ) -> anyhow::Result<(Vec<AccessListItem>, u64)> {
+        let txn = self.db.begin()?;
+        let CallEnv {
+            block_number,
+            header,
+            block_spec,
+            chain_id,
+            sender,
+            message,
+        } = self.call_env(&txn, call_data, block_id)?;
+
+        let recipient = match message.action() {
+            TransactionAction::Call(to) => to,
+            TransactionAction::Create => {
+                akula::execution::address::create_address(sender, message.nonce())
+            }
+        };
+        let excluded = [sender, recipient]
+            .into_iter()
+            .chain(executor::precompiles(block_spec.revision))
+            .collect::<Vec<_>>();
+
+        // Accessing the listed slots may change the execution path and touch new ones, so
+        // keep re-running with the recorded list until it stops growing.
+        let mut previous = AccessListTracer::new(executor::access_list(&message), excluded.clone());
+        loop {
+            let access_list = previous.access_list();
+            let message = executor::with_access_list(&message, chain_id, access_list.clone());
+
+            let mut buffer = Buffer::new(&txn, Some(block_number));
+            let mut state = IntraBlockState::new(&mut buffer);
+            let mut tracer = AccessListTracer::new(&access_list, excluded.clone());
+
+            let result = executor::execute_message(
+                &mut state,
+                &mut tracer,
+                &mut self.analysis_cache.local(),
+                &header,
+                &block_spec,
+                &message,
+                sender,
+            )?;
+
+            if tracer.same_access_list(&previous) {
+                return Ok((access_list, result.gas_used));
+            }
+            previous = tracer;
+        }
+    }
+
+    /// Executes the calls one after another as transactions on top of the block, each
+    /// seeing the state left by the previous ones, like `eth_callBundle`.
+    pub fn simulate_bundle(
+        &self,
+        calls: Vec<(types::MessageCall, Option<u64>)>,
+        block_id: types::BlockId,
+        state_override: &StateOverride,
+        block_override: &BlockOverride,
+    ) -> anyhow::Result<Vec<Result<BundleTransactionResult, InvalidTransaction>>> {
+        let txn = self.db.begin()?;
+
+        let (block_number, block_hash) = helpers::resolve_block_id(&txn, block_id)?
+            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+        let chain_spec = chain::chain_config::read(&txn)?
+            .ok_or_else(|| format_err!("chain specification not found"))?;
+        let mut header: PartialHeader = chain::header::read(&txn, block_hash, block_number)?
+            .ok_or_else(|| format_err!("Header not found for #{block_number}/{block_hash}"))?
+            .into();
+        block_override.apply(&mut header);
+        let block_spec = chain_spec.collect_block_spec(header.number);
+
+        let messages = calls
+            .into_iter()
+            .map(|(call_data, nonce)| {
+                let (sender, message) = helpers::convert_message_call(
+                    &Buffer::new(&txn, Some(block_number)),
+                    chain_spec.params.chain_id,
+                    call_data,
+                    &header,
+                    U256::ZERO,
+                    Some(self.call_gas_limit),
+                )?;
+                Ok((sender, message, nonce))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut buffer = Buffer::new(&txn, Some(block_number));
+        let mut state = IntraBlockState::new(&mut buffer);
+        executor::apply_state_override(&mut state, state_override)?;
+        let mut analysis_cache = self.analysis_cache.local();
+
+        // Messages are converted against the block's state, so each one's nonce is only
+        // known once the transactions before it ran.
+        messages
+            .into_iter()
+            .map(|(sender, message, nonce)| {
+                if let Err(e) =
+                    executor::validate_transaction(&mut state, &header, &message, sender, nonce)?
+                {
+                    return Ok(Err(e));
+                }
+                let message = executor::with_nonce(&message, state.get_nonce(sender)?);
+                let result = executor::execute_transaction(
+                    &mut state,
+                    &mut NoopTracer,
+                    &mut analysis_cache,
+                    &header,
+                    &block_spec,
+                    &message,
+                    sender,
+                )?;
+                Ok(Ok(BundleTransactionResult {
+                    status_code: result.status_code,
+                    output: result.output_data,
+                    gas_used: result.gas_used,
+                    logs: result.logs,
+                }))
+            })
+            .collect()
+    }
+
+    /// Finds the lowest gas limit the call succeeds with, searching between its intrinsic
+    /// gas and its gas limit, capped by what the sender can pay for, the way geth does.
+    pub fn estimate_gas(
+        &self,
+        call_data: types::MessageCall,
+        block_id: types::BlockId,
+        state_override: &StateOverride,
+    ) -> anyhow::Result<GasEstimate> {
+        let txn = self.db.begin()?;
+        let (block_number, hash) = helpers::resolve_block_id(&txn, block_id)?
+            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+
+        let chain_spec = chain::chain_config::read(&txn)?
+            .ok_or_else(|| format_err!("chain specification not found"))?;
+        let block_spec = chain_spec.collect_block_spec(block_number);
+        let header = chain::header::read(&txn, hash, block_number)?
+            .ok_or_else(|| format_err!("no header found for block #{block_number}/{hash}"))?
+            .into();
+
+        // Without an explicit gas limit the call may use up to the block gas limit.
+        let (sender, message) = helpers::convert_message_call(
+            &Buffer::new(&txn, Some(block_number)),
+            chain_spec.params.chain_id,
+            call_data,
+            &header,
+            U256::ZERO,
+            None,
+        )?;
+
+        let mut hi = message.gas_limit();
+        let fee_cap = message.max_fee_per_gas();
+        if fee_cap > U256::ZERO {
+            let mut buffer = Buffer::new(&txn, Some(block_number));
+            let mut state = IntraBlockState::new(&mut buffer);
+            executor::apply_state_override(&mut state, state_override)?;
+
+            let available = state
+                .get_balance(sender)?
+                .checked_sub(message.value())
+                .ok_or_else(|| format_err!("insufficient funds for transfer"))?;
+            let allowance = available / fee_cap;
+            if allowance < U256::from(hi) {
+                hi = allowance.as_u64();
+            }
+        }
+
+        let mut analysis_cache = self.analysis_cache.local();
+        let mut run = |gas_limit: u64| {
+            let mut buffer = Buffer::new(&txn, Some(block_number));
+            let mut state = IntraBlockState::new(&mut buffer);
+            executor::apply_state_override(&mut state, state_override)?;
+            executor::execute_message(
+                &mut state,
+                &mut NoopTracer,
+                &mut analysis_cache,
+                &header,
+                &block_spec,
+                &executor::with_gas_limit(&message, gas_limit),
+                sender,
+            )
+        };
+
+        let result = run(hi)?;
+        if result.status_code != StatusCode::Success {
+            return Ok(GasEstimate::Failed {
+                status_code: result.status_code,
+                output: result.output_data,
+            });
+        }
+
+        // A successful run needs at least the gas it ended up using.
+        let mut lo = result
+            .gas_used
+            .max(executor::intrinsic_gas(&message, block_spec.revision))
+            - 1;
+        while lo + 1 < hi {
+            let mid = lo + (hi - lo) / 2;
+            if run(mid)?.status_code == StatusCode::Success {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+
+        Ok(GasEstimate::Gas(hi))
+    }
+
+    /// Returns the block the sandbox sits on, pinning it if needed.
+    pub fn sandbox_base(&self, sandbox: &mut Sandbox) -> anyhow::Result<BlockNumber> {
+        if let Some(base) = sandbox.base {
+            return Ok(base);
+        }
+
+        let txn = self.db.begin()?;
+        let (base, _) = helpers::resolve_block_id(&txn, types::BlockNumber::Latest)?
+            .ok_or_else(|| format_err!("failed to resolve latest block"))?;
+        sandbox.base = Some(base);
+        Ok(base)
+    }
+
+    /// Executes the calls one after another as transactions in the block following the
+    /// sandbox's base and folds the effects of each into the sandbox as soon as it ran,
+    /// handing its hash, or why it could not be included, to `reply`.
+    ///
+    /// All calls execute on one in-memory state that starts from the sandbox's overlay, so
+    /// each builds on the ones before it. Reads the overlay doesn't answer go to the
+    /// database through a read transaction that is only open until `calls` runs out.
+    pub fn sandbox_execute<R>(
+        &self,
+        sandbox: &mut Sandbox,
+        calls: impl IntoIterator<Item = (types::MessageCall, Option<u64>, R)>,
+        mut reply: impl FnMut(R, anyhow::Result<Result<H256, InvalidTransaction>>),
+    ) -> anyhow::Result<()> {
+        let base = self.sandbox_base(sandbox)?;
+        let txn = self.db.begin()?;
+
+        let base_hash = chain::canonical_hash::read(&txn, base)?
+            .ok_or_else(|| format_err!("no canonical header for block #{base:?}"))?;
+        let base_header = chain::header::read(&txn, base_hash, base)?
+            .ok_or_else(|| format_err!("header not found for block #{base}/{base_hash}"))?;
+        let chain_spec = chain::chain_config::read(&txn)?
+            .ok_or_else(|| format_err!("chain specification not found"))?;
+
+        let mut header = PartialHeader::from(base_header.clone());
+        header.parent_hash = base_hash;
+        header.number = BlockNumber(base.0 + 1);
+        let block_spec = chain_spec.collect_block_spec(header.number);
+        header.base_fee_per_gas = if block_spec.revision >= Revision::London {
+            Some(Self::next_base_fee_per_gas(&txn, &base_header)?)
+        } else {
+            None
+        };
+
+        let mut buffer = Buffer::new(&txn, Some(base));
+        let mut state = IntraBlockState::new(&mut buffer);
+        executor::apply_state_override(&mut state, sandbox.state())?;
+        let mut analysis_cache = self.analysis_cache.local();
+
+        for (call_data, nonce, r) in calls {
+            let (sender, message) = match helpers::convert_message_call(
+                &Buffer::new(&txn, Some(base)),
+                chain_spec.params.chain_id,
+                call_data,
+                &header,
+                U256::ZERO,
+                Some(header.gas_limit),
+            ) {
+                Ok(converted) => converted,
+                Err(e) => {
+                    reply(r, Err(e));
+                    continue;
+                }
+            };
+
+            if !sandbox.fits(header.gas_limit, message.gas_limit()) {
+                reply(r, Ok(Err(InvalidTransaction::GasLimitReached)));
+                continue;
+            }
+            match executor::validate_transaction(&mut state, &header, &message, sender, nonce) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    reply(r, Ok(Err(e)));
+                    continue;
+                }
+                Err(e) => {
+                    reply(r, Err(format_err!("{e}")));
+                    return Err(e);
+                }
+            }
+            let nonce = state.get_nonce(sender)?;
+            let message = executor::with_nonce(&message, nonce);
+            let hash = keccak256([sender.as_bytes(), &nonce.to_be_bytes()[..]].concat());
+
+            let mut tracer = StateDiffTracer::default();
+            let result = match executor::execute_transaction(
+                &mut state,
+                &mut tracer,
+                &mut analysis_cache,
+                &header,
+                &block_spec,
+                &message,
+                sender,
+            ) {
+                Ok(result) => result,
+                Err(e) => {
+                    // The state may have been left half-way through the transaction, so
+                    // nothing can be executed on it anymore.
+                    reply(r, Err(format_err!("{e}")));
+                    return Err(e);
+                }
+            };
+
+            // Fold the state the transaction may have changed into the overlay, which
+            // queries against the sandbox read.
+            let mut touched = tracer.into_transactions().pop().unwrap_or_default();
+            touched.accounts.insert(sender);
+            touched.accounts.insert(header.beneficiary);
+            touched.accounts.extend(touched.storage.keys().copied());
+            let mut changes = StateOverride::new();
+            for address in touched.accounts {
+                let change = if state.exists(address)? {
+                    let mut state_diff = HashMap::new();
+                    for slot in touched.storage.get(&address).into_iter().flatten() {
+                        state_diff.insert(
+                            *slot,
+                            state.get_current_storage(address, U256::from_be_bytes(slot.0))?,
+                        );
+                    }
+                    AccountOverride {
+                        balance: Some(state.get_balance(address)?),
+                        nonce: Some(state.get_nonce(address)?),
+                        code: Some(state.get_code(address)?.unwrap_or_default()),
+                        state: None,
+                        state_diff: Some(state_diff),
+                    }
+                } else {
+                    AccountOverride {
+                        balance: Some(U256::ZERO),
+                        nonce: Some(0),
+                        code: Some(Bytes::new()),
+                        state: Some(HashMap::new()),
+                        state_diff: None,
+                    }
+                };
+                changes.insert(address, change);
+            }
+            sandbox.record(
+                hash,
+                header.number,
+                sender,
+                nonce,
+                &message,
+                &result,
+                changes,
+            );
+
+            reply(r, Ok(Ok(hash)));
+        }
+
+        Ok(())
+    }
+
+    pub fn fee_history(
+        &self,
+        block_count: u64,
+        last_block: types::BlockNumber,
+        reward_percentiles: &[f64],
+        cancel: &Cancellation,
+    ) -> anyhow::Result<FeeHistory> {
+        validate_reward_percentiles(reward_percentiles)?;
+
+        let txn = self.db.begin()?;
+        let (last_block, _) = helpers::resolve_block_id(&txn, last_block)?
+            .ok_or_else(|| format_err!("failed to resolve block {last_block:?}"))?;
+        let block_count = block_count
+            .min(MAX_FEE_HISTORY_BLOCKS)
+            .min(last_block.0 + 1);
+
+        let mut history = FeeHistory {
+            oldest_block: BlockNumber(last_block.0 + 1 - block_count),
+            base_fee_per_gas: Vec::with_capacity(block_count as usize + 1),
+            gas_used_ratio: Vec::with_capacity(block_count as usize),
+            reward: vec![],
+        };
+        let mut last_header = None;
+        for block_number in history.oldest_block.0..=last_block.0 {
+            let block_number = BlockNumber(block_number);
+            let block_hash = chain::canonical_hash::read(&txn, block_number)?
+                .ok_or_else(|| format_err!("no canonical header for block #{block_number:?}"))?;
+            let header = chain::header::read(&txn, block_hash, block_number)?.ok_or_else(|| {
+                format_err!("header not found for block #{block_number}/{block_hash}")
+            })?;
+            let base_fee_per_gas = header.base_fee_per_gas.unwrap_or(U256::ZERO);
+
+            history.base_fee_per_gas.push(base_fee_per_gas);
+            history
+                .gas_used_ratio
+                .push(header.gas_used as f64 / header.gas_limit as f64);
+
+            if !reward_percentiles.is_empty() {
+                let fees = if header.gas_used > 0 {
+                    let (block_body, receipts) = self.execute_block(
+                        &txn,
+                        block_number,
+                        block_hash,
+                        header.clone().into(),
+                        None,
+                        &mut NoopTracer,
+                        cancel,
+                    )?;
+                    block_body
+                        .transactions
+                        .iter()
+                        .zip(&receipts)
+                        .scan(0, |cumulative_gas_used, (transaction, receipt)| {
+                            let gas_used = receipt.cumulative_gas_used - *cumulative_gas_used;
+                            *cumulative_gas_used = receipt.cumulative_gas_used;
+                            Some((
+                                effective_priority_fee_per_gas(
+                                    &transaction.message,
+                                    base_fee_per_gas,
+                                ),
+                                gas_used,
+                            ))
+                        })
+                        .collect::<Vec<_>>()
+                } else {
+                    vec![]
+                };
+                history.reward.push(percentile_rewards(
+                    fees,
+                    header.gas_used,
+                    reward_percentiles,
+                ));
+            }
+
+            last_header = Some(header);
+        }
+
+        if let Some(header) = last_header {
+            history
+                .base_fee_per_gas
+                .push(Self::next_base_fee_per_gas(&txn, &header)?);
+        }
+
+        Ok(history)
+    }
+
+    /// Suggests fees from the effective priority fees paid in the last `blocks` blocks.
+    pub fn suggest_fees(&self, blocks: u64, percentile: f64) -> anyhow::Result<FeeSuggestion> {
+        if !(0.0..=100.0).contains(&percentile) {
+            return Err(format_err!("invalid percentile {percentile}"));
+        }
+
+        let txn = self.db.begin()?;
+        let (last_block, last_hash) = helpers::resolve_block_id(&txn, types::BlockNumber::Latest)?
+            .ok_or_else(|| format_err!("failed to resolve latest block"))?;
+        let last_header = chain::header::read(&txn, last_hash, last_block)?
+            .ok_or_else(|| format_err!("header not found for block #{last_block}/{last_hash}"))?;
+
+        let mut fees = vec![];
+        for block_number in last_block.0.saturating_sub(blocks.saturating_sub(1))..=last_block.0 {
+            let block_number = BlockNumber(block_number);
+            let block_hash = chain::canonical_hash::read(&txn, block_number)?
+                .ok_or_else(|| format_err!("no canonical header for block #{block_number:?}"))?;
+            let header = chain::header::read(&txn, block_hash, block_number)?.ok_or_else(|| {
+                format_err!("header not found for block #{block_number}/{block_hash}")
+            })?;
+            let base_fee_per_gas = header.base_fee_per_gas.unwrap_or(U256::ZERO);
+
+            if let Some(block_body) =
+                chain::block_body::read_without_senders(&txn, block_hash, block_number)?
+            {
+                fees.extend(block_body.transactions.iter().map(|transaction| {
+                    effective_priority_fee_per_gas(&transaction.message, base_fee_per_gas)
+                }));
+            }
+        }
+        fees.sort_unstable();
+
+        let max_priority_fee_per_gas = if fees.is_empty() {
+            U256::from(DEFAULT_PRIORITY_FEE)
+        } else {
+            fees[((fees.len() - 1) as f64 * percentile / 100.0) as usize]
+        };
+
+        Ok(FeeSuggestion {
+            base_fee_per_gas: Self::next_base_fee_per_gas(&txn, &last_header)?,
+            max_priority_fee_per_gas,
+        })
+    }
+
+    pub fn get_balance(&self, address: Address, block_id: types::BlockId) -> anyhow::Result<U256> {
+        let txn = self.db.begin()?;
+
+        let (block_number, _) = helpers::resolve_block_id(&txn, block_id)?
+            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+        Ok(state::account::read(&txn, address, Some(block_number))?
+            .map(|acc| acc.balance)
+            .unwrap_or(U256::ZERO))
+    }
+
+    pub fn get_block(
+        &self,
+        block_id: types::BlockId,
+        include_txs: bool,
+    ) -> anyhow::Result<Option<types::Block>> {
+        Ok(helpers::construct_block(
+            &self.db.begin()?,
+            block_id,
+            include_txs,
+            None,
+        )?)
+    }
+
+    pub fn get_transaction_by_hash(
+        &self,
+        hash: H256,
+    ) -> anyhow::Result<Option<types::Transaction>> {
+        let txn = self.db.begin()?;
+        if let Some(block_number) = chain::tl::read(&txn, hash)? {
+            let block_hash = chain::canonical_hash::read(&txn, block_number)?
+                .ok_or_else(|| format_err!("canonical hash for block #{block_number} not found"))?;
+            let (index, transaction) = chain::block_body::read_without_senders(
+                                &txn,
+                                block_hash,
+                                block_number,
+                            )?.ok_or_else(|| format_err!("body not found for block #{block_number}/{block_hash}"))?
+                        .transactions
+                        .into_iter()
+                        .enumerate()
+                        .find(|(_, tx)| tx.hash() == hash)
+                        .ok_or_else(|| {
+                                    format_err!(
+                                            "tx with hash {hash} is not found in block #{block_number}/{block_hash} - tx lookup index invalid?"
+                                        )
+                                })?;
+            let senders = chain::tx_sender::read(&txn, block_hash, block_number)?;
+            let sender = *senders
+                .get(index)
+                .ok_or_else(|| format_err!("senders to short: {index} vs len {}", senders.len()))?;
+            return Ok(Some(types::Transaction {
+                hash,
+                nonce: transaction.nonce().into(),
+                block_hash: Some(block_hash),
+                block_number: Some(block_number.0.into()),
+                from: sender,
+                gas: transaction.gas_limit().into(),
+                gas_price: match transaction.message {
+                    Message::Legacy { gas_price, .. } => gas_price,
+                    Message::EIP2930 { gas_price, .. } => gas_price,
+                    Message::EIP1559 {
+                        max_fee_per_gas, ..
+                    } => max_fee_per_gas,
+                },
+                input: transaction.input().clone().into(),
+                to: match transaction.action() {
+                    TransactionAction::Call(to) => Some(to),
+                    TransactionAction::Create => None,
+                },
+                transaction_index: Some(U64::from(index)),
+                value: transaction.value(),
+                v: transaction.v().into(),
+                r: transaction.r(),
+                s: transaction.s(),
+            }));
+        }
+
+        Ok(None)
+    }
+
+    pub fn get_code(
+        &self,
+        address: Address,
+        block_id: types::BlockId,
+    ) -> anyhow::Result<types::Bytes> {
+        let txn = self.db.begin()?;
+        let (block_number, _) = helpers::resolve_block_id(&txn, block_id)?
+            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+        Ok(
+            if let Some(account) = state::account::read(&txn, address, Some(block_number))? {
+                txn.get(tables::Code, account.code_hash)?
+                    .ok_or_else(|| {
+                        format_err!("failed to find code for code hash {}", account.code_hash)
+                    })?
+                    .into()
+            } else {
+                Default::default()
+            },
+        )
+    }
+
+    pub fn get_logs(
+        &self,
+        filter: LogFilter,
+        cancel: &Cancellation,
+    ) -> anyhow::Result<Vec<types::TransactionLog>> {
+        let txn = self.db.begin()?;
+
+        let (from, to) = match filter.blocks {
+            LogFilterBlocks::Range { from, to } => {
+                let (from, _) = helpers::resolve_block_id(&txn, from)?
+                    .ok_or_else(|| format_err!("failed to resolve block {from:?}"))?;
+                let (to, _) = helpers::resolve_block_id(&txn, to)?
+                    .ok_or_else(|| format_err!("failed to resolve block {to:?}"))?;
+                (from, to)
+            }
+            LogFilterBlocks::Hash(hash) => {
+                let (block_number, _) =
+                    helpers::resolve_block_id(&txn, types::BlockId::Hash(hash))?
+                        .ok_or_else(|| format_err!("failed to resolve block {hash}"))?;
+                (block_number, block_number)
+            }
+        };
+        if from > to {
+            return Err(format_err!("invalid block range #{from}..#{to}"));
+        }
+        if to.0 - from.0 >= MAX_LOG_QUERY_BLOCKS {
+            return Err(format_err!(
+                "log query spans more than {MAX_LOG_QUERY_BLOCKS} blocks"
+            ));
+        }
+
+        let mut logs = vec![];
+        for block_number in from.0..=to.0 {
+            let block_number = BlockNumber(block_number);
+            let block_hash = chain::canonical_hash::read(&txn, block_number)?
+                .ok_or_else(|| format_err!("no canonical header for block #{block_number:?}"))?;
+            let header = chain::header::read(&txn, block_hash, block_number)?.ok_or_else(|| {
+                format_err!("header not found for block #{block_number}/{block_hash}")
+            })?;
+            // Blocks without gas usage have no transactions and hence no logs.
+            if header.gas_used == 0 || !filter.may_match_bloom(&header.logs_bloom) {
+                continue;
+            }
+
+            let (block_body, receipts) = self.execute_block(
+                &txn,
+                block_number,
+                block_hash,
+                header.into(),
+                None,
+                &mut NoopTracer,
+                cancel,
+            )?;
+
+            let mut log_index = 0_usize;
+            for (transaction_index, (transaction, receipt)) in
+                block_body.transactions.iter().zip(&receipts).enumerate()
+            {
+                for log in &receipt.logs {
+                    if filter.matches(log) {
+                        logs.push(types::TransactionLog {
+                            log_index: Some(U64::from(log_index)),
+                            transaction_index: Some(U64::from(transaction_index)),
+                            transaction_hash: Some(transaction.hash()),
+                            block_hash: Some(block_hash),
+                            block_number: Some(U64::from(block_number.0)),
+                            address: log.address,
+                            data: log.data.clone().into(),
+                            topics: log.topics.clone(),
+                        });
+                    }
+                    log_index += 1;
+                }
+            }
+        }
+
+        Ok(logs)
+    }
+
+    /// Builds the account and storage proofs of `address` at the given block.
+    ///
+    /// Intermediate trie nodes are only stored for the head state, so the tries are built
+    /// from the stored branch nodes, recomputing only the subtries that hold accounts or
+    /// slots changed after the block, rolled back to their values at the block.
+    pub fn get_proof(
+        &self,
+        address: Address,
+        locations: Vec<H256>,
+        block_id: types::BlockId,
+    ) -> anyhow::Result<AccountProof> {
+        let txn = self.db.begin()?;
+        let (block_number, _) = helpers::resolve_block_id(&txn, block_id)?
+            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+        let changed = Self::changed_accounts(&txn, block_number)?;
+
+        let hashed_address = keccak256(address);
+        let account = state::account::read(&txn, address, Some(block_number))?;
+
+        let hashed_locations = locations.iter().map(keccak256).collect::<Vec<_>>();
+        let storage_changes = if changed.contains_key(&hashed_address) {
+            Self::hashed_storage_changes(&txn, address, block_number)?
+        } else {
+            BTreeMap::new()
+        };
+        let storage_trie =
+            Self::storage_trie(&txn, hashed_address, storage_changes, &hashed_locations)?;
+        let storage_proof = locations
+            .iter()
+            .zip(storage_trie.proofs)
+            .map(|(location, proof)| {
+                Ok(StorageProof {
+                    key: *location,
+                    value: state::storage::read(
+                        &txn,
+                        address,
+                        U256::from_be_bytes(location.0),
+                        Some(block_number),
+                    )?,
+                    proof: proof.into_iter().map(Bytes::from).collect(),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let account_trie = ProofBuilder::new(&[hashed_address]).build_from(&mut AccountTrie {
+            txn: &txn,
+            block_number,
+            changed: &changed,
+            target: (hashed_address, storage_trie.root),
+        })?;
+
+        let (balance, nonce, code_hash) = account
+            .map_or((U256::ZERO, 0, H256::zero()), |account| {
+                (account.balance, account.nonce, account.code_hash)
+            });
+        Ok(AccountProof {
+            address,
+            balance,
+            nonce,
+            code_hash,
+            storage_hash: storage_trie.root,
+            account_proof: account_trie
+                .proofs
+                .into_iter()
+                .next()
+                .unwrap_or_default()
+                .into_iter()
+                .map(Bytes::from)
+                .collect(),
+            storage_proof,
+        })
+    }
+
+    pub fn get_storage_at(
+        &self,
+        address: Address,
+        key: U256,
+        block_id: types::BlockId,
+    ) -> anyhow::Result<U256> {
+        let txn = self.db.begin()?;
+        let (block_number, _) = helpers::resolve_block_id(&txn, block_id)?
+            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+        Ok(state::storage::read(
+            &txn,
+            address,
+            key,
+            Some(block_number),
+        )?)
+    }
+
+    /// Returns up to `limit` non-zero storage slots of the account at the block, starting
+    /// at `start` if given, like `debug_storageRangeAt` but keyed by plain slot.
+    pub fn storage_range(
+        &self,
+        address: Address,
+        block_id: types::BlockId,
+        start: Option<H256>,
+        limit: usize,
+    ) -> anyhow::Result<StorageRange> {
+        let txn = self.db.begin()?;
+        let (block_number, _) = helpers::resolve_block_id(&txn, block_id)?
+            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+
+        let mut history_cursor = txn.cursor(tables::StorageHistory)?;
+        let changes = Self::changed_slots(&txn, &mut history_cursor, address, start, block_number);
+        let mut cursor = txn.cursor(tables::Storage)?;
+        let mut slots = Overlaid::new(cursor.walk_dup(address, start), changes);
+
+        let mut range = StorageRange::default();
+        for entry in slots.by_ref().take(limit) {
+            let (location, value) = entry?;
+            range.storage.insert(location, value);
+        }
+        range.next_key = slots.next().transpose()?.map(|(location, _)| location);
+
+        Ok(range)
+    }
+
+    /// Visits every account that exists at the block in address order, until `visit`
+    /// returns `false`, like `debug_dumpBlock`. Everything is read from a single database
+    /// transaction, and accounts and slots that changed after the block are found lazily
+    /// through the history indices.
+    pub fn dump_state(
+        &self,
+        block_id: types::BlockId,
+        include_storage: bool,
+        mut visit: impl FnMut(DumpAccount) -> bool,
+    ) -> anyhow::Result<()> {
+        let txn = self.db.begin()?;
+        let (block_number, _) = helpers::resolve_block_id(&txn, block_id)?
+            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+
+        // Accounts that changed after the block may be gone from plain state.
+        let mut history_cursor = txn.cursor(tables::AccountHistory)?;
+        let mut changed =
+            Self::changed_after(&mut history_cursor, Address::zero(), block_number).peekable();
+
+        let mut account_cursor = txn.cursor(tables::Account)?;
+        let mut storage_cursor = txn.cursor(tables::Storage)?;
+        let mut storage_history_cursor = txn.cursor(tables::StorageHistory)?;
+        let mut plain = account_cursor.walk(None).peekable();
+        loop {
+            let plain_address = match plain.peek() {
+                Some(Ok((address, _))) => Some(*address),
+                Some(Err(_)) => return plain.next().unwrap().map(|_| ()),
+                None => None,
+            };
+            let changed_address = match changed.peek() {
+                Some(Ok(address)) => Some(*address),
+                Some(Err(_)) => return changed.next().unwrap().map(|_| ()),
+                None => None,
+            };
+            let address = match (plain_address, changed_address) {
+                (Some(plain_address), Some(changed_address)) if changed_address < plain_address => {
+                    changed.next();
+                    changed_address
+                }
+                (Some(plain_address), changed_address) => {
+                    plain.next();
+                    if changed_address == Some(plain_address) {
+                        changed.next();
+                    }
+                    plain_address
+                }
+                (None, Some(changed_address)) => {
+                    changed.next();
+                    changed_address
+                }
+                (None, None) => break,
+            };
+
+            let account = match state::account::read(&txn, address, Some(block_number))? {
+                Some(account) => account,
+                None => continue,
+            };
+            let code = if account.code_hash == EMPTY_HASH {
+                Bytes::new()
+            } else {
+                txn.get(tables::Code, account.code_hash)?.ok_or_else(|| {
+                    format_err!("failed to find code for code hash {}", account.code_hash)
+                })?
+            };
+            let storage = if include_storage {
+                Some(
+                    Overlaid::new(
+                        storage_cursor.walk_dup(address, None),
+                        Self::changed_slots(
+                            &txn,
+                            &mut storage_history_cursor,
+                            address,
+                            None,
+                            block_number,
+                        ),
+                    )
+                    .collect::<anyhow::Result<BTreeMap<_, _>>>()?,
+                )
+            } else {
+                None
+            };
+
+            if !visit(DumpAccount {
+                address,
+                balance: account.balance,
+                nonce: account.nonce,
+                code_hash: account.code_hash,
+                code,
+                storage,
+            }) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns how every account and storage slot changed between the state at `from_block`
+    /// and the state at `to_block`, read from the change sets of the blocks in between.
+    /// Accounts and slots that end up as they were at `from_block` are left out.
+    pub fn state_diff(
+        &self,
+        from_block: types::BlockId,
+        to_block: types::BlockId,
+    ) -> anyhow::Result<BTreeMap<Address, AccountChange>> {
+        let txn = self.db.begin()?;
+        let (from, _) = helpers::resolve_block_id(&txn, from_block)?
+            .ok_or_else(|| format_err!("failed to resolve block {from_block:?}"))?;
+        let (to, _) = helpers::resolve_block_id(&txn, to_block)?
+            .ok_or_else(|| format_err!("failed to resolve block {to_block:?}"))?;
+        if from > to {
+            return Err(format_err!("block #{from} is after block #{to}"));
+        }
+
+        let mut prior = PriorState::default();
+        Self::visit_changes(&txn, BlockNumber(from.0 + 1)..=to, |change| {
+            prior.record(change);
+            Ok(())
+        })?;
+
+        // Code is only loaded for the accounts left once net-unchanged state is pruned.
+        prior
+            .net_changes(
+                |address| state::account::read(&txn, address, Some(to)),
+                |address, location| {
+                    state::storage::read(&txn, address, U256::from_be_bytes(location.0), Some(to))
+                },
+            )?
+            .into_iter()
+            .map(|(address, change)| {
+                let (before, after) = match change.account {
+                    Some((before, after)) => (
+                        Self::with_code(&txn, before)?,
+                        Self::with_code(&txn, after)?,
+                    ),
+                    None => {
+                        let account = Self::account_state(&txn, address, to)?;
+                        (account.clone(), account)
+                    }
+                };
+                Ok((
+                    address,
+                    AccountChange {
+                        before,
+                        after,
+                        storage: change.storage,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    /// Returns the blocks from `from_block` to `to_block` inclusive in which the account's
+    /// balance, nonce or code changed, read from the account history index.
+    pub fn account_history(
+        &self,
+        address: Address,
+        from_block: types::BlockId,
+        to_block: types::BlockId,
+    ) -> anyhow::Result<Vec<BlockNumber>> {
+        let txn = self.db.begin()?;
+        let (from, _) = helpers::resolve_block_id(&txn, from_block)?
+            .ok_or_else(|| format_err!("failed to resolve block {from_block:?}"))?;
+        let (to, _) = helpers::resolve_block_id(&txn, to_block)?
+            .ok_or_else(|| format_err!("failed to resolve block {to_block:?}"))?;
+
+        Self::history_blocks(&txn, tables::AccountHistory, address, from..=to)
+    }
+
+    /// Returns the blocks from `from_block` to `to_block` inclusive in which the storage
+    /// slot changed, each with the slot's value after that block, read from the storage
+    /// history index.
+    pub fn storage_history(
+        &self,
+        address: Address,
+        location: H256,
+        from_block: types::BlockId,
+        to_block: types::BlockId,
+    ) -> anyhow::Result<Vec<(BlockNumber, U256)>> {
+        let txn = self.db.begin()?;
+        let (from, _) = helpers::resolve_block_id(&txn, from_block)?
+            .ok_or_else(|| format_err!("failed to resolve block {from_block:?}"))?;
+        let (to, _) = helpers::resolve_block_id(&txn, to_block)?
+            .ok_or_else(|| format_err!("failed to resolve block {to_block:?}"))?;
+
+        let blocks =
+            Self::history_blocks(&txn, tables::StorageHistory, (address, location), from..=to)?;
+
+        // The state at a block is read from the change set of the slot's next change.
+        let key = U256::from_be_bytes(location.0);
+        blocks
+            .into_iter()
+            .map(|block_number| {
+                Ok((
+                    block_number,
+                    state::storage::read(&txn, address, key, Some(block_number))?,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn get_transaction_count(
+        &self,
+        address: Address,
+        block_id: types::BlockId,
+    ) -> anyhow::Result<U64> {
+        let txn = self.db.begin()?;
+        let (block_number, _) = helpers::resolve_block_id(&txn, block_id)?
+            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+
+        Ok(state::account::read(&txn, address, Some(block_number))?
+            .map(|account| account.nonce)
             .unwrap_or(0)
             .into())
     }
 
-    pub async fn get_transaction_receipt(
+    pub fn get_transaction_receipt(
+        &self,
+        hash: H256,
+        cancel: &Cancellation,
+    ) -> anyhow::Result<Option<types::TransactionReceipt>> {
+        let txn = self.db.begin()?;
+
+        if let Some((block_number, block_hash, header, transaction_index)) =
+            Self::locate_transaction(&txn, hash)?
+        {
+            let (block_body, receipts) = self.execute_block(
+                &txn,
+                block_number,
+                block_hash,
+                header,
+                Some(transaction_index),
+                &mut NoopTracer,
+                cancel,
+            )?;
+
+            return Ok(
+                Self::block_receipts(block_number, block_hash, &block_body, &receipts)
+                    .nth(transaction_index),
+            );
+        }
+
+        Ok(None)
+    }
+
+    /// Executes the block once and returns the receipts of all of its transactions.
+    pub fn get_block_receipts(
+        &self,
+        block_id: types::BlockId,
+        cancel: &Cancellation,
+    ) -> anyhow::Result<Option<Vec<types::TransactionReceipt>>> {
+        let txn = self.db.begin()?;
+        let (block_number, block_hash) = match helpers::resolve_block_id(&txn, block_id)? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let header = chain::header::read(&txn, block_hash, block_number)?.ok_or_else(|| {
+            format_err!("header not found for block #{block_number}/{block_hash}")
+        })?;
+        // Blocks without gas usage have no transactions.
+        if header.gas_used == 0 {
+            return Ok(Some(vec![]));
+        }
+
+        let (block_body, receipts) = self.execute_block(
+            &txn,
+            block_number,
+            block_hash,
+            header.into(),
+            None,
+            &mut NoopTracer,
+            cancel,
+        )?;
+
+        Ok(Some(
+            Self::block_receipts(block_number, block_hash, &block_body, &receipts).collect(),
+        ))
+    }
+
+    pub fn get_uncle_by_block_number_and_index(
+        &self,
+        block_id: types::BlockId,
+        index: U64,
+    ) -> anyhow::Result<Option<types::Block>> {
+        let txn = self.db.begin()?;
+        Ok(helpers::construct_block(
+            &txn,
+            block_id,
+            false,
+            Some(index),
+        )?)
+    }
+
+    pub fn get_uncle_count(&self, block_id: types::BlockId) -> anyhow::Result<U64> {
+        let txn = self.db.begin()?;
+        let (block_number, block_hash) = helpers::resolve_block_id(&txn, block_id)?
+            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+
+        Ok(U64::from(
+            chain::storage_body::read(&txn, block_hash, block_number)?
+                .map(|body| body.uncles.len())
+                .unwrap_or(0),
+        ))
+    }
+
+    /// Executes the call and returns its output along with its call traces.
+    pub fn trace_call(
+        &self,
+        call_data: types::MessageCall,
+        block_id: types::BlockId,
+    ) -> anyhow::Result<(Bytes, Vec<ethers::types::TransactionTrace>)> {
+        let txn = self.db.begin()?;
+        let CallEnv {
+            block_number,
+            header,
+            block_spec,
+            sender,
+            message,
+            ..
+        } = self.call_env(&txn, call_data, block_id)?;
+
+        let mut buffer = Buffer::new(&txn, Some(block_number));
+        let mut state = IntraBlockState::new(&mut buffer);
+        let mut tracer = ParityTracer::default();
+
+        let result = executor::execute_message(
+            &mut state,
+            &mut tracer,
+            &mut self.analysis_cache.local(),
+            &header,
+            &block_spec,
+            &message,
+            sender,
+        )?;
+
+        Ok((
+            result.output_data,
+            tracer.into_transactions().pop().unwrap_or_default(),
+        ))
+    }
+
+    /// Replays the block up to the transaction and returns the transaction's call traces.
+    pub fn trace_transaction(
+        &self,
+        hash: H256,
+        cancel: &Cancellation,
+    ) -> anyhow::Result<Option<Vec<ethers::types::Trace>>> {
+        let txn = self.db.begin()?;
+
+        if let Some((block_number, block_hash, header, transaction_index)) =
+            Self::locate_transaction(&txn, hash)?
+        {
+            let mut tracer = ParityTracer::default();
+            self.execute_block(
+                &txn,
+                block_number,
+                block_hash,
+                header,
+                Some(transaction_index),
+                &mut tracer,
+                cancel,
+            )?;
+
+            let traces = tracer
+                .into_transactions()
+                .into_iter()
+                .nth(transaction_index)
+                .ok_or_else(|| format_err!("transaction {hash} was not traced"))?;
+
+            return Ok(Some(tracer::localize_traces(
+                traces,
+                transaction_index,
+                hash,
+                block_number,
+                block_hash,
+            )));
+        }
+
+        Ok(None)
+    }
+
+    /// Executes the call and returns its geth-style struct logs.
+    pub fn debug_trace_call(
+        &self,
+        call_data: types::MessageCall,
+        block_id: types::BlockId,
+        config: StructLoggerConfig,
+    ) -> anyhow::Result<ethers::types::GethTrace> {
+        let txn = self.db.begin()?;
+        let CallEnv {
+            block_number,
+            header,
+            block_spec,
+            sender,
+            message,
+            ..
+        } = self.call_env(&txn, call_data, block_id)?;
+
+        let mut buffer = Buffer::new(&txn, Some(block_number));
+        let mut state = IntraBlockState::new(&mut buffer);
+        let mut tracer = StructLogger::new(config);
+
+        let result = executor::execute_message(
+            &mut state,
+            &mut tracer,
+            &mut self.analysis_cache.local(),
+            &header,
+            &block_spec,
+            &message,
+            sender,
+        )?;
+        let (struct_logs, _) = tracer.into_parts();
+
+        Ok(ethers::types::GethTrace {
+            failed: result.status_code != StatusCode::Success,
+            gas: result.gas_used,
+            return_value: result.output_data.into(),
+            struct_logs,
+        })
+    }
+
+    /// Replays the block up to the transaction and returns the transaction's geth-style
+    /// struct logs.
+    pub fn debug_trace_transaction(
         &self,
         hash: H256,
-    ) -> anyhow::Result<Option<types::TransactionReceipt>> {
+        config: StructLoggerConfig,
+        cancel: &Cancellation,
+    ) -> anyhow::Result<Option<ethers::types::GethTrace>> {
         let txn = self.db.begin()?;
 
-        if let Some(block_number) = chain::tl::read(&txn, hash)? {
-            let block_hash = chain::canonical_hash::read(&txn, block_number)?
-                .ok_or_else(|| format_err!("no canonical header for block #{block_number:?}"))?;
-            let header = PartialHeader::from(
-                chain::header::read(&txn, block_hash, block_number)?.ok_or_else(|| {
-                    format_err!("header not found for block #{block_number}/{block_hash}")
-                })?,
-            );
+        if let Some((block_number, block_hash, header, transaction_index)) =
+            Self::locate_transaction(&txn, hash)?
+        {
+            let mut tracer = StructLogger::skipping(config, transaction_index);
+            let (_, receipts) = self.execute_block(
+                &txn,
+                block_number,
+                block_hash,
+                header,
+                Some(transaction_index),
+                &mut tracer,
+                cancel,
+            )?;
+
+            let receipt = &receipts[transaction_index];
+            let gas_used = receipt.cumulative_gas_used
+                - transaction_index
+                    .checked_sub(1)
+                    .map_or(0, |last_index| receipts[last_index].cumulative_gas_used);
+            let (struct_logs, return_value) = tracer.into_parts();
+
+            return Ok(Some(ethers::types::GethTrace {
+                failed: !receipt.success,
+                gas: gas_used,
+                return_value: return_value.into(),
+                struct_logs,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the call traces of every transaction in the block followed by its reward traces.
+    pub fn trace_block(
+        &self,
+        block_id: types::BlockId,
+        cancel: &Cancellation,
+    ) -> anyhow::Result<Option<Vec<ethers::types::Trace>>> {
+        let txn = self.db.begin()?;
+        let (block_number, block_hash) = match helpers::resolve_block_id(&txn, block_id)? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        // The genesis block has neither transactions nor rewards.
+        if block_number.0 == 0 {
+            return Ok(Some(vec![]));
+        }
+        let header = chain::header::read(&txn, block_hash, block_number)?.ok_or_else(|| {
+            format_err!("header not found for block #{block_number}/{block_hash}")
+        })?;
+
+        let mut tracer = ParityTracer::default();
+        let (block_body, _) = self.execute_block(
+            &txn,
+            block_number,
+            block_hash,
+            header.clone().into(),
+            None,
+            &mut tracer,
+            cancel,
+        )?;
+
+        let mut traces = vec![];
+        for (transaction_index, (transaction, transaction_traces)) in block_body
+            .transactions
+            .iter()
+            .zip(tracer.into_transactions())
+            .enumerate()
+        {
+            traces.extend(tracer::localize_traces(
+                transaction_traces,
+                transaction_index,
+                transaction.hash(),
+                block_number,
+                block_hash,
+            ));
+        }
+        traces.extend(Self::reward_traces(&txn, block_hash, &header)?);
+
+        Ok(Some(traces))
+    }
+
+    /// Replays every transaction of the block in a single pass and returns the requested
+    /// traces of each.
+    ///
+    /// For state diffs the processor is stepped one transaction at a time, after the
+    /// block-level changes that precede the transactions, so that the state can be read
+    /// between them.
+    pub fn trace_replay_block_transactions(
+        &self,
+        block_id: types::BlockId,
+        trace_types: TraceTypes,
+        cancel: &Cancellation,
+    ) -> anyhow::Result<Option<Vec<ethers::types::BlockTrace>>> {
+        let txn = self.db.begin()?;
+        let (block_number, block_hash) = match helpers::resolve_block_id(&txn, block_id)? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        if block_number.0 == 0 {
+            return Ok(Some(vec![]));
+        }
+        let header = PartialHeader::from(
+            chain::header::read(&txn, block_hash, block_number)?.ok_or_else(|| {
+                format_err!("header not found for block #{block_number}/{block_hash}")
+            })?,
+        );
+        let parent_number = BlockNumber(block_number.0 - 1);
+
+        let mut parity_tracer = ParityTracer::default();
+        let mut vm_tracer = VmTracer::default();
+        let (block_body, vm_traces, state_diffs) = if trace_types.state_diff {
             let block_body = chain::block_body::read_with_senders(&txn, block_hash, block_number)?
                 .ok_or_else(|| {
                     format_err!("body not found for block #{block_number}/{block_hash}")
                 })?;
             let chain_spec = chain::chain_config::read(&txn)?
                 .ok_or_else(|| format_err!("chain specification not found"))?;
+            let block_spec = chain_spec.collect_block_spec(block_number);
 
-            // Prepare the execution context.
-            let mut buffer = Buffer::new(&txn, Some(BlockNumber(block_number.0 - 1)));
+            let state_diff_tracer = RefCell::new(StateDiffTracer::default());
+            let mut state_diff_events = SharedTracer(&state_diff_tracer);
+            let mut tracers: Vec<&mut dyn Tracer> =
+                vec![&mut parity_tracer, &mut state_diff_events];
+            if trace_types.vm_trace {
+                tracers.push(&mut vm_tracer);
+            }
+            let mut tracer = MultiTracer(tracers);
 
-            let block_execution_spec = chain_spec.collect_block_spec(block_number);
+            let mut buffer = Buffer::new(&txn, Some(parent_number));
             let mut engine = engine_factory(None, chain_spec)?;
-            let mut analysis_cache = AnalysisCache::default();
-            let mut tracer = NoopTracer;
-
+            let mut analysis_cache = self.analysis_cache.local();
             let mut processor = ExecutionProcessor::new(
                 &mut buffer,
                 &mut tracer,
@@ -314,97 +2382,1050 @@ where
                 &mut *engine,
                 &header,
                 &block_body,
-                &block_execution_spec,
+                &block_spec,
             );
+            // Stops before the first transaction, once irregular state changes are applied.
+            processor.execute_block_no_post_validation_while(|_, _| false)?;
 
-            let transaction_index = chain::block_body::read_without_senders(&txn, block_hash, block_number)?.ok_or_else(|| format_err!("where's block body"))?.transactions
-                    .into_iter()
-                    .enumerate()
-                    .find(|(_, tx)| tx.hash() == hash)
-                    .ok_or_else(|| format_err!("transaction {hash} not found in block #{block_number}/{block_hash} despite lookup index"))?.0;
-
-            let receipts =
-                processor.execute_block_no_post_validation_while(|i, _| i <= transaction_index)?;
-
-            let transaction = &block_body.transactions[transaction_index];
-            let receipt = receipts.get(transaction_index).unwrap();
-            let gas_used = U64::from(
-                receipt.cumulative_gas_used
-                    - transaction_index
-                        .checked_sub(1)
-                        .and_then(|last_index| receipts.get(last_index))
-                        .map(|receipt| receipt.cumulative_gas_used)
-                        .unwrap_or(0),
-            );
-            let logs = receipt
-                .logs
-                .iter()
-                .enumerate()
-                .map(|(i, log)| types::TransactionLog {
-                    log_index: Some(U64::from(i)),
-                    transaction_index: Some(U64::from(transaction_index)),
-                    transaction_hash: Some(transaction.hash()),
-                    block_hash: Some(block_hash),
-                    block_number: Some(U64::from(block_number.0)),
-                    address: log.address,
-                    data: log.data.clone().into(),
-                    topics: log.topics.clone(),
-                })
-                .collect::<Vec<_>>();
+            // Latest known state of every account and slot touched so far in the block.
+            let mut accounts = HashMap::<Address, Option<AccountState>>::new();
+            let mut storage = HashMap::<(Address, H256), U256>::new();
+            let mut state_diffs = Vec::with_capacity(block_body.transactions.len());
+            for transaction in &block_body.transactions {
+                cancel.check()?;
+                processor.execute_transaction(&transaction.message, transaction.sender)?;
+                let state = processor.state();
 
-            return Ok(Some(types::TransactionReceipt {
-                transaction_hash: hash,
-                transaction_index: U64::from(transaction_index),
+                let mut touched = state_diff_tracer.borrow_mut().take_last();
+                touched.accounts.insert(transaction.sender);
+                touched.accounts.insert(header.beneficiary);
+                touched.accounts.extend(touched.storage.keys().copied());
+
+                let mut changes = BTreeMap::new();
+                for address in touched.accounts {
+                    let before = match accounts.get(&address) {
+                        Some(account) => account.clone(),
+                        None => Self::account_state(&txn, address, parent_number)?,
+                    };
+                    let after = if state.exists(address)? {
+                        Some(AccountState {
+                            balance: state.get_balance(address)?,
+                            nonce: state.get_nonce(address)?,
+                            code: state.get_code(address)?.unwrap_or_default(),
+                        })
+                    } else {
+                        None
+                    };
+
+                    let mut slots = BTreeMap::new();
+                    for slot in touched.storage.get(&address).into_iter().flatten() {
+                        let location = U256::from_be_bytes(slot.0);
+                        let before = match storage.get(&(address, *slot)) {
+                            Some(value) => *value,
+                            None => {
+                                state::storage::read(&txn, address, location, Some(parent_number))?
+                            }
+                        };
+                        let after = state.get_current_storage(address, location)?;
+                        storage.insert((address, *slot), after);
+                        slots.insert(*slot, (before, after));
+                    }
+
+                    accounts.insert(address, after.clone());
+                    changes.insert(
+                        address,
+                        AccountChange {
+                            before,
+                            after,
+                            storage: slots,
+                        },
+                    );
+                }
+                state_diffs.push(Some(utils::account_changes_to_state_diff(changes)));
+            }
+
+            drop(processor);
+
+            let vm_traces = vm_tracer.into_transactions(|address| {
+                Ok(Self::account_state(&txn, address, block_number)?
+                    .map(|account| account.code)
+                    .unwrap_or_default())
+            })?;
+            (block_body, vm_traces, state_diffs)
+        } else {
+            let mut tracers: Vec<&mut dyn Tracer> = vec![&mut parity_tracer];
+            if trace_types.vm_trace {
+                tracers.push(&mut vm_tracer);
+            }
+            let (block_body, _) = self.execute_block(
+                &txn,
+                block_number,
                 block_hash,
-                block_number: U64::from(block_number.0),
-                from: transaction.sender,
-                to: transaction.message.action().into_address(),
-                cumulative_gas_used: receipt.cumulative_gas_used.into(),
-                gas_used,
-                contract_address: if let TransactionAction::Create = transaction.message.action() {
-                    Some(akula::execution::address::create_address(
-                        transaction.sender,
-                        transaction.message.nonce(),
-                    ))
-                } else {
-                    None
-                },
-                logs,
-                logs_bloom: receipt.bloom,
-                status: if receipt.success {
-                    U64::from(1_u16)
-                } else {
-                    U64::zero()
-                },
-            }));
-        }
+                header,
+                None,
+                &mut MultiTracer(tracers),
+                cancel,
+            )?;
 
-        Ok(None)
+            // Contracts created in the block only exist in the state that follows it.
+            let vm_traces = vm_tracer.into_transactions(|address| {
+                Ok(Self::account_state(&txn, address, block_number)?
+                    .map(|account| account.code)
+                    .unwrap_or_default())
+            })?;
+            let state_diffs = vec![None; block_body.transactions.len()];
+            (block_body, vm_traces, state_diffs)
+        };
+
+        let outputs = parity_tracer.outputs().to_vec();
+        let mut vm_traces = vm_traces.into_iter();
+        Ok(Some(
+            block_body
+                .transactions
+                .iter()
+                .zip(outputs)
+                .zip(parity_tracer.into_transactions())
+                .zip(state_diffs)
+                .map(
+                    |(((transaction, output), traces), state_diff)| ethers::types::BlockTrace {
+                        output: output.into(),
+                        trace: trace_types.trace.then_some(traces),
+                        vm_trace: if trace_types.vm_trace {
+                            vm_traces.next()
+                        } else {
+                            None
+                        },
+                        state_diff,
+                        transaction_hash: Some(transaction.hash()),
+                    },
+                )
+                .collect(),
+        ))
+    }
+
+    /// Finds the block of a canonical transaction and its index in the block.
+    fn locate_transaction<K: TransactionKind>(
+        txn: &MdbxTransaction<'_, K, DB>,
+        hash: H256,
+    ) -> anyhow::Result<Option<(BlockNumber, H256, PartialHeader, usize)>> {
+        let block_number = match chain::tl::read(txn, hash)? {
+            Some(block_number) => block_number,
+            None => return Ok(None),
+        };
+        let block_hash = chain::canonical_hash::read(txn, block_number)?
+            .ok_or_else(|| format_err!("no canonical header for block #{block_number:?}"))?;
+        let header = PartialHeader::from(
+            chain::header::read(txn, block_hash, block_number)?.ok_or_else(|| {
+                format_err!("header not found for block #{block_number}/{block_hash}")
+            })?,
+        );
+
+        let transaction_index = chain::block_body::read_without_senders(txn, block_hash, block_number)?
+            .ok_or_else(|| format_err!("body not found for block #{block_number}/{block_hash}"))?
+            .transactions
+            .into_iter()
+            .position(|tx| tx.hash() == hash)
+            .ok_or_else(|| format_err!("transaction {hash} not found in block #{block_number}/{block_hash} despite lookup index"))?;
+
+        Ok(Some((block_number, block_hash, header, transaction_index)))
     }
 
-    pub async fn get_uncle_by_block_number_and_index(
+    fn call_env<K: TransactionKind>(
         &self,
+        txn: &MdbxTransaction<'_, K, DB>,
+        call_data: types::MessageCall,
         block_id: types::BlockId,
-        index: U64,
-    ) -> anyhow::Result<Option<types::Block>> {
-        let txn = self.db.begin()?;
-        Ok(helpers::construct_block(
-            &txn,
-            block_id,
-            false,
-            Some(index),
-        )?)
+    ) -> anyhow::Result<CallEnv> {
+        let (block_number, block_hash) = helpers::resolve_block_id(txn, block_id)?
+            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+        let chain_spec = chain::chain_config::read(txn)?
+            .ok_or_else(|| format_err!("chain specification not found"))?;
+        let chain_id = chain_spec.params.chain_id;
+        let block_spec = chain_spec.collect_block_spec(block_number);
+
+        let header = chain::header::read(txn, block_hash, block_number)?
+            .ok_or_else(|| format_err!("Header not found for #{block_number}/{block_hash}"))?
+            .into();
+
+        let (sender, message) = helpers::convert_message_call(
+            &Buffer::new(txn, Some(block_number)),
+            chain_id,
+            call_data,
+            &header,
+            U256::ZERO,
+            Some(self.call_gas_limit),
+        )?;
+
+        Ok(CallEnv {
+            block_number,
+            header,
+            block_spec,
+            chain_id,
+            sender,
+            message,
+        })
     }
 
-    pub async fn get_uncle_count(&self, block_id: types::BlockId) -> anyhow::Result<U64> {
-        let txn = self.db.begin()?;
-        let (block_number, block_hash) = helpers::resolve_block_id(&txn, block_id)?
-            .ok_or_else(|| format_err!("failed to resolve block {block_id:?}"))?;
+    /// Executes the call on top of the state at `block_number`, in a block with the given
+    /// header.
+    fn execute_call<K: TransactionKind>(
+        &self,
+        txn: &MdbxTransaction<'_, K, DB>,
+        chain_spec: &ChainSpec,
+        block_number: BlockNumber,
+        header: &PartialHeader,
+        call_data: types::MessageCall,
+        state_override: &StateOverride,
+    ) -> anyhow::Result<(StatusCode, types::Bytes)> {
+        let mut buffer = Buffer::new(txn, Some(block_number));
 
-        Ok(U64::from(
-            chain::storage_body::read(&txn, block_hash, block_number)?
-                .map(|body| body.uncles.len())
-                .unwrap_or(0),
-        ))
+        let (sender, message) = helpers::convert_message_call(
+            &buffer,
+            chain_spec.params.chain_id,
+            call_data,
+            header,
+            U256::ZERO,
+            Some(self.call_gas_limit),
+        )?;
+
+        let mut state = IntraBlockState::new(&mut buffer);
+        executor::apply_state_override(&mut state, state_override)?;
+
+        let mut analysis_cache = self.analysis_cache.local();
+        let block_spec = chain_spec.collect_block_spec(header.number);
+
+        let mut tracer = NoopTracer;
+
+        executor::prime_code_analysis(&mut state, &mut analysis_cache, &message)?;
+        let result = evmglue::execute(
+            &mut state,
+            &mut tracer,
+            &mut analysis_cache,
+            header,
+            &block_spec,
+            &message,
+            sender,
+            message.gas_limit(),
+        )?;
+
+        Ok((result.status_code, result.output_data.into()))
+    }
+
+    /// Re-executes the block on top of its parent state and returns its body with the
+    /// receipts of the first `up_to + 1` transactions, or of all of them if `up_to` is `None`.
+    /// Fails between transactions once `cancel` is set.
+    #[allow(clippy::too_many_arguments)]
+    fn execute_block<K: TransactionKind>(
+        &self,
+        txn: &MdbxTransaction<'_, K, DB>,
+        block_number: BlockNumber,
+        block_hash: H256,
+        header: PartialHeader,
+        up_to: Option<usize>,
+        tracer: &mut dyn Tracer,
+        cancel: &Cancellation,
+    ) -> anyhow::Result<(BlockBodyWithSenders, Vec<Receipt>)> {
+        let block_body = chain::block_body::read_with_senders(txn, block_hash, block_number)?
+            .ok_or_else(|| format_err!("body not found for block #{block_number}/{block_hash}"))?;
+        let chain_spec = chain::chain_config::read(txn)?
+            .ok_or_else(|| format_err!("chain specification not found"))?;
+
+        // Prepare the execution context.
+        let mut buffer = Buffer::new(txn, Some(BlockNumber(block_number.0 - 1)));
+
+        let block_execution_spec = chain_spec.collect_block_spec(block_number);
+        let mut engine = engine_factory(None, chain_spec)?;
+        let mut analysis_cache = self.analysis_cache.local();
+
+        let mut processor = ExecutionProcessor::new(
+            &mut buffer,
+            tracer,
+            &mut analysis_cache,
+            &mut *engine,
+            &header,
+            &block_body,
+            &block_execution_spec,
+        );
+
+        let receipts = processor.execute_block_no_post_validation_while(|i, _| {
+            !cancel.is_cancelled() && up_to.map_or(true, |up_to| i <= up_to)
+        })?;
+        cancel.check()?;
+
+        Ok((block_body, receipts))
+    }
+
+    /// Builds the RPC receipts of the executed prefix of a block, deriving per-transaction
+    /// gas used and block-wide log indices from the receipts that precede each one.
+    fn block_receipts<'a>(
+        block_number: BlockNumber,
+        block_hash: H256,
+        block_body: &'a BlockBodyWithSenders,
+        receipts: &'a [Receipt],
+    ) -> impl Iterator<Item = types::TransactionReceipt> + 'a {
+        let mut cumulative_gas_used = 0;
+        let mut log_index = 0;
+        block_body
+            .transactions
+            .iter()
+            .zip(receipts)
+            .enumerate()
+            .map(move |(transaction_index, (transaction, receipt))| {
+                let gas_used = U64::from(receipt.cumulative_gas_used - cumulative_gas_used);
+                cumulative_gas_used = receipt.cumulative_gas_used;
+
+                let transaction_hash = transaction.hash();
+                let logs = receipt
+                    .logs
+                    .iter()
+                    .map(|log| {
+                        log_index += 1;
+                        types::TransactionLog {
+                            log_index: Some(U64::from(log_index - 1)),
+                            transaction_index: Some(U64::from(transaction_index)),
+                            transaction_hash: Some(transaction_hash),
+                            block_hash: Some(block_hash),
+                            block_number: Some(U64::from(block_number.0)),
+                            address: log.address,
+                            data: log.data.clone().into(),
+                            topics: log.topics.clone(),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                types::TransactionReceipt {
+                    transaction_hash,
+                    transaction_index: U64::from(transaction_index),
+                    block_hash,
+                    block_number: U64::from(block_number.0),
+                    from: transaction.sender,
+                    to: transaction.message.action().into_address(),
+                    cumulative_gas_used: receipt.cumulative_gas_used.into(),
+                    gas_used,
+                    contract_address: if let TransactionAction::Create =
+                        transaction.message.action()
+                    {
+                        Some(akula::execution::address::create_address(
+                            transaction.sender,
+                            transaction.message.nonce(),
+                        ))
+                    } else {
+                        None
+                    },
+                    logs,
+                    logs_bloom: receipt.bloom,
+                    status: if receipt.success {
+                        U64::from(1_u16)
+                    } else {
+                        U64::zero()
+                    },
+                }
+            })
+    }
+
+    /// Returns the rewards the consensus engine pays out at the end of the block.
+    fn reward_traces<K: TransactionKind>(
+        txn: &MdbxTransaction<'_, K, DB>,
+        block_hash: H256,
+        header: &BlockHeader,
+    ) -> anyhow::Result<Vec<ethers::types::Trace>> {
+        let block_number = header.number;
+        let chain_spec = chain::chain_config::read(txn)?
+            .ok_or_else(|| format_err!("chain specification not found"))?;
+        let revision = chain_spec.collect_block_spec(block_number).revision;
+        let ommers = chain::storage_body::read(txn, block_hash, block_number)?
+            .map(|body| body.uncles)
+            .unwrap_or_default();
+        let engine = engine_factory(None, chain_spec)?;
+
+        // The beneficiary's reward comes first, followed by one per ommer.
+        Ok(engine
+            .finalize(&header.clone().into(), &ommers, revision)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, change)| match change {
+                FinalizationChange::Reward { address, amount } => tracer::reward_trace(
+                    address,
+                    amount,
+                    if i == 0 {
+                        ethers::types::RewardType::Block
+                    } else {
+                        ethers::types::RewardType::Uncle
+                    },
+                    block_number,
+                    block_hash,
+                ),
+            })
+            .collect())
+    }
+
+    fn account_state<K: TransactionKind>(
+        txn: &MdbxTransaction<'_, K, DB>,
+        address: Address,
+        block_number: BlockNumber,
+    ) -> anyhow::Result<Option<AccountState>> {
+        Self::with_code(txn, state::account::read(txn, address, Some(block_number))?)
+    }
+
+    fn with_code<K: TransactionKind>(
+        txn: &MdbxTransaction<'_, K, DB>,
+        account: Option<Account>,
+    ) -> anyhow::Result<Option<AccountState>> {
+        account
+            .map(|account| {
+                let code = if account.code_hash == EMPTY_HASH {
+                    Bytes::new()
+                } else {
+                    txn.get(tables::Code, account.code_hash)?.ok_or_else(|| {
+                        format_err!("failed to find code for code hash {}", account.code_hash)
+                    })?
+                };
+                Ok(AccountState {
+                    balance: account.balance,
+                    nonce: account.nonce,
+                    code,
+                })
+            })
+            .transpose()
+    }
+
+    /// Returns the base fee of the block following `header`.
+    fn next_base_fee_per_gas<K: TransactionKind>(
+        txn: &MdbxTransaction<'_, K, DB>,
+        header: &BlockHeader,
+    ) -> anyhow::Result<U256> {
+        let next_block = BlockNumber(header.number.0 + 1);
+        let next_header = match chain::canonical_hash::read(txn, next_block)? {
+            Some(next_hash) => chain::header::read(txn, next_hash, next_block)?,
+            None => None,
+        };
+
+        Ok(if let Some(next_header) = next_header {
+            next_header.base_fee_per_gas.unwrap_or(U256::ZERO)
+        } else if let Some(base_fee_per_gas) = header.base_fee_per_gas {
+            calculate_next_base_fee_per_gas(header.gas_used, header.gas_limit, base_fee_per_gas)
+        } else if chain::chain_config::read(txn)?
+            .ok_or_else(|| format_err!("chain specification not found"))?
+            .collect_block_spec(next_block)
+            .revision
+            >= Revision::London
+        {
+            U256::from(INITIAL_BASE_FEE)
+        } else {
+            U256::ZERO
+        })
+    }
+
+    /// Blocks within `blocks` in which a history index records a change of `inner`. Each
+    /// chunk of the index is keyed by the highest block it holds, so the walk seeks to the
+    /// chunk holding the first block and stops after the one holding the last.
+    fn history_blocks<K, T, I>(
+        txn: &MdbxTransaction<'_, K, DB>,
+        table: T,
+        inner: I,
+        blocks: RangeInclusive<BlockNumber>,
+    ) -> anyhow::Result<Vec<BlockNumber>>
+    where
+        K: TransactionKind,
+        T: Table<Key = BitmapKey<I>, SeekKey = BitmapKey<I>>,
+        T::Value: TableDecode,
+        for<'a> &'a T::Value: IntoIterator<Item = u64>,
+        BitmapKey<I>: TableDecode,
+        I: Copy + PartialEq,
+    {
+        let mut found = Vec::new();
+        let mut cursor = txn.cursor(table)?;
+        for entry in cursor.walk(Some(BitmapKey {
+            inner,
+            block_number: *blocks.start(),
+        })) {
+            let (key, bitmap) = entry?;
+            if key.inner != inner {
+                break;
+            }
+            found.extend(
+                (&bitmap)
+                    .into_iter()
+                    .map(BlockNumber)
+                    .filter(|block_number| blocks.contains(block_number)),
+            );
+            if key.block_number >= *blocks.end() {
+                break;
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Keys of a history index from `start` on that changed after `block_number`, in key
+    /// order. The first chunk of a key past `block_number` tells whether the key changed
+    /// later, so the index is walked lazily without reading any change set.
+    fn changed_after<'c, 'tx: 'c, K, T, I>(
+        cursor: &'c mut MdbxCursor<'tx, K, T>,
+        start: I,
+        block_number: BlockNumber,
+    ) -> impl Iterator<Item = anyhow::Result<I>> + 'c
+    where
+        K: TransactionKind,
+        T: Table<Key = BitmapKey<I>, SeekKey = BitmapKey<I>>,
+        T::Value: TableDecode,
+        for<'a> &'a T::Value: IntoIterator<Item = u64>,
+        BitmapKey<I>: TableDecode,
+        I: Copy + PartialEq + 'c,
+    {
+        let mut last = None;
+        cursor
+            .walk(Some(BitmapKey {
+                inner: start,
+                block_number: BlockNumber(block_number.0 + 1),
+            }))
+            .filter_map(move |entry| {
+                let (key, bitmap) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(e)),
+                };
+                if key.block_number <= block_number || last == Some(key.inner) {
+                    return None;
+                }
+                last = Some(key.inner);
+
+                (&bitmap)
+                    .into_iter()
+                    .any(|changed| changed > block_number.0)
+                    .then(|| Ok(key.inner))
+            })
+    }
+
+    /// Storage slots of `address` from `start` on that changed after `block_number`, in slot
+    /// order, each with its value at `block_number` or `None` if it was empty then.
+    fn changed_slots<'c, 'tx: 'c, K: TransactionKind>(
+        txn: &'c MdbxTransaction<'tx, K, DB>,
+        cursor: &'c mut MdbxCursor<'tx, K, tables::StorageHistory>,
+        address: Address,
+        start: Option<H256>,
+        block_number: BlockNumber,
+    ) -> impl Iterator<Item = anyhow::Result<(H256, Option<U256>)>> + 'c {
+        Self::changed_after(cursor, (address, start.unwrap_or_default()), block_number)
+            .take_while(move |entry| {
+                entry
+                    .as_ref()
+                    .map_or(true, |(changed_address, _)| *changed_address == address)
+            })
+            .map(move |entry| {
+                let (_, location) = entry?;
+                let value = state::storage::read(
+                    txn,
+                    address,
+                    U256::from_be_bytes(location.0),
+                    Some(block_number),
+                )?;
+                Ok((location, Some(value).filter(|value| *value != U256::ZERO)))
+            })
+    }
+
+    /// Visits the account changes of the blocks in `blocks`, then their storage changes,
+    /// each in block order. Plain state is at the latest block, and change sets hold the
+    /// value prior to their block, so the first change of an entry visited is its value at
+    /// the block before the range, and any later change of it can be skipped.
+    fn visit_changes<K: TransactionKind>(
+        txn: &MdbxTransaction<'_, K, DB>,
+        blocks: RangeInclusive<BlockNumber>,
+        mut visit: impl FnMut(Change) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let mut cursor = txn.cursor(tables::AccountChangeSet)?;
+        for entry in cursor.walk(Some(*blocks.start())) {
+            let (block_number, change) = entry?;
+            if block_number > *blocks.end() {
+                break;
+            }
+            visit(Change::Account {
+                address: change.address,
+                account: change.account,
+            })?;
+        }
+
+        let mut cursor = txn.cursor(tables::StorageChangeSet)?;
+        for entry in cursor.walk(Some(*blocks.start())) {
+            let (key, change) = entry?;
+            if key.block_number > *blocks.end() {
+                break;
+            }
+            visit(Change::Storage {
+                address: key.address,
+                location: change.location,
+                value: change.value,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Accounts whose account or storage changed after `block_number`, by hashed address.
+    ///
+    /// Found through the history indices, which hold a bitmap of blocks per account and
+    /// slot rather than an entry per change, so old blocks don't cost a walk over every
+    /// later change. Fails past `MAX_PROOF_CHANGED_ACCOUNTS` accounts.
+    fn changed_accounts<K: TransactionKind>(
+        txn: &MdbxTransaction<'_, K, DB>,
+        block_number: BlockNumber,
+    ) -> anyhow::Result<BTreeMap<H256, Address>> {
+        let mut changed = BTreeMap::new();
+        let mut insert = |address: Address| {
+            changed.entry(keccak256(address)).or_insert(address);
+            if changed.len() > MAX_PROOF_CHANGED_ACCOUNTS {
+                return Err(format_err!(
+                    "more than {MAX_PROOF_CHANGED_ACCOUNTS} accounts changed after block #{block_number}"
+                ));
+            }
+            Ok(())
+        };
+
+        let mut cursor = txn.cursor(tables::AccountHistory)?;
+        for address in Self::changed_after(&mut cursor, Address::zero(), block_number) {
+            insert(address?)?;
+        }
+
+        // One changed slot is enough to change the account's storage root, so the walk
+        // skips to the next account after it.
+        let mut cursor = txn.cursor(tables::StorageHistory)?;
+        let mut start = Some(Address::zero());
+        while let Some(from) = start.take() {
+            if let Some(entry) =
+                Self::changed_after(&mut cursor, (from, H256::zero()), block_number).next()
+            {
+                let (address, _) = entry?;
+                insert(address)?;
+                start = next_address(address);
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// Values at `block_number` of the account's storage slots that changed after it, by
+    /// hashed slot.
+    fn hashed_storage_changes<K: TransactionKind>(
+        txn: &MdbxTransaction<'_, K, DB>,
+        address: Address,
+        block_number: BlockNumber,
+    ) -> anyhow::Result<BTreeMap<H256, Option<U256>>> {
+        let mut cursor = txn.cursor(tables::StorageHistory)?;
+        Self::changed_slots(txn, &mut cursor, address, None, block_number)
+            .map(|entry| {
+                let (location, value) = entry?;
+                Ok((keccak256(location), value))
+            })
+            .collect()
+    }
+
+    fn storage_trie<K: TransactionKind>(
+        txn: &MdbxTransaction<'_, K, DB>,
+        hashed_address: H256,
+        changes: BTreeMap<H256, Option<U256>>,
+        targets: &[H256],
+    ) -> anyhow::Result<proof::TrieProof> {
+        ProofBuilder::new(targets).build_from(&mut StorageTrie {
+            txn,
+            hashed_address,
+            changes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(address: Address, topics: Vec<H256>) -> Log {
+        Log {
+            address,
+            topics,
+            data: Bytes::new(),
+        }
+    }
+
+    fn filter(addresses: Option<Vec<Address>>, topics: [Option<Vec<H256>>; 4]) -> LogFilter {
+        LogFilter {
+            blocks: LogFilterBlocks::Hash(H256::zero()),
+            addresses,
+            topics,
+        }
+    }
+
+    #[test]
+    fn bloom_bits_of_input() {
+        assert_eq!(
+            bloom_bits(&[0x11; 20]).collect::<Vec<_>>(),
+            vec![(167, 1), (127, 16), (231, 2)]
+        );
+        assert_eq!(
+            bloom_bits(&[0x22; 32]).collect::<Vec<_>>(),
+            vec![(104, 32), (195, 2), (215, 128)]
+        );
+    }
+
+    #[test]
+    fn logs_bloom_contains_addresses_and_topics() {
+        let address = Address::repeat_byte(0x11);
+        let topic = H256::repeat_byte(0x22);
+        let bloom = logs_bloom(&[log(address, vec![topic])]);
+
+        let mut expected = Bloom::zero();
+        for (byte, mask) in [
+            (167, 1),
+            (127, 16),
+            (231, 2),
+            (104, 32),
+            (195, 2),
+            (215, 128),
+        ] {
+            expected.as_bytes_mut()[byte] |= mask;
+        }
+        assert_eq!(bloom, expected);
+
+        assert!(bloom_contains(&bloom, address.as_bytes()));
+        assert!(bloom_contains(&bloom, topic.as_bytes()));
+        assert!(!bloom_contains(&bloom, H256::repeat_byte(0x33).as_bytes()));
+        assert_eq!(logs_bloom(&[]), Bloom::zero());
+    }
+
+    #[test]
+    fn log_filter_matches() {
+        let address = Address::repeat_byte(0x11);
+        let (a, b, c) = (
+            H256::repeat_byte(0x22),
+            H256::repeat_byte(0x33),
+            H256::repeat_byte(0x44),
+        );
+        let entry = log(address, vec![a, b]);
+
+        assert!(filter(None, Default::default()).matches(&entry));
+        assert!(filter(Some(vec![Address::zero(), address]), Default::default()).matches(&entry));
+        assert!(!filter(Some(vec![Address::zero()]), Default::default()).matches(&entry));
+
+        // Topics are positional, with `None` as a wildcard.
+        assert!(filter(None, [None, Some(vec![b]), None, None]).matches(&entry));
+        assert!(filter(None, [Some(vec![c, a]), Some(vec![b]), None, None]).matches(&entry));
+        assert!(!filter(None, [Some(vec![b]), None, None, None]).matches(&entry));
+        // A log without a topic at a constrained position doesn't match.
+        assert!(!filter(None, [None, None, Some(vec![c]), None]).matches(&entry));
+    }
+
+    #[test]
+    fn log_filter_may_match_bloom() {
+        let address = Address::repeat_byte(0x11);
+        let topic = H256::repeat_byte(0x22);
+        let bloom = logs_bloom(&[log(address, vec![topic])]);
+
+        assert!(
+            filter(Some(vec![address]), [Some(vec![topic]), None, None, None])
+                .may_match_bloom(&bloom)
+        );
+        assert!(!filter(Some(vec![Address::zero()]), Default::default()).may_match_bloom(&bloom));
+        assert!(!filter(
+            None,
+            [None, Some(vec![H256::repeat_byte(0x33)]), None, None]
+        )
+        .may_match_bloom(&bloom));
+    }
+
+    #[test]
+    fn next_base_fee_per_gas() {
+        let base_fee_per_gas = U256::new(1_000_000_000);
+        let next =
+            |gas_used| calculate_next_base_fee_per_gas(gas_used, 30_000_000, base_fee_per_gas);
+
+        assert_eq!(next(15_000_000), base_fee_per_gas);
+        assert_eq!(next(30_000_000), U256::new(1_125_000_000));
+        assert_eq!(next(0), U256::new(875_000_000));
+        assert_eq!(next(22_500_000), U256::new(1_062_500_000));
+        // Blocks above the target raise the base fee by at least one.
+        assert_eq!(
+            calculate_next_base_fee_per_gas(15_000_001, 30_000_000, U256::ONE),
+            U256::new(2)
+        );
+        assert_eq!(
+            calculate_next_base_fee_per_gas(1, 1, base_fee_per_gas),
+            base_fee_per_gas
+        );
+    }
+
+    #[test]
+    fn call_blocks_resolve_once_each() {
+        let latest = types::BlockId::Number(types::BlockNumber::Latest);
+        let number = types::BlockId::Number(types::BlockNumber::Number(5.into()));
+        let hash = types::BlockId::Hash(H256::repeat_byte(5));
+        let unknown = types::BlockId::Number(types::BlockNumber::Number(99.into()));
+
+        let mut lookups = Vec::new();
+        let resolved = resolve_distinct(
+            [latest, number, hash, unknown, number, latest, unknown],
+            |block_id| {
+                lookups.push(block_id);
+                Ok(match block_id {
+                    types::BlockId::Number(types::BlockNumber::Latest) => Some(7),
+                    types::BlockId::Number(types::BlockNumber::Number(n)) if n.as_u64() == 5 => {
+                        Some(5)
+                    }
+                    types::BlockId::Hash(_) => Some(5),
+                    _ => None,
+                })
+            },
+        )
+        .unwrap();
+
+        assert_eq!(lookups, vec![latest, number, hash, unknown]);
+        assert_eq!(
+            resolved
+                .iter()
+                .map(|block| block.as_ref().ok().copied())
+                .collect::<Vec<_>>(),
+            vec![Some(7), Some(5), Some(5), None, Some(5), Some(7), None]
+        );
+        assert_eq!(
+            resolved[6].as_ref().unwrap_err().to_string(),
+            format!("block {unknown:?} not found")
+        );
+    }
+
+    #[test]
+    fn next_address_carries() {
+        assert_eq!(
+            next_address(Address::zero()),
+            Some(Address::from_low_u64_be(1))
+        );
+        assert_eq!(
+            next_address(Address::from_low_u64_be(0x01ff)),
+            Some(Address::from_low_u64_be(0x0200))
+        );
+        assert_eq!(next_address(Address::repeat_byte(0xff)), None);
+    }
+
+    #[test]
+    fn reward_percentiles_must_ascend_within_bounds() {
+        assert!(validate_reward_percentiles(&[]).is_ok());
+        assert!(validate_reward_percentiles(&[0.0, 25.0, 25.0, 100.0]).is_ok());
+        assert_eq!(
+            validate_reward_percentiles(&[10.0, 100.5])
+                .unwrap_err()
+                .to_string(),
+            "invalid reward percentile: 100.5"
+        );
+        assert!(validate_reward_percentiles(&[-1.0]).is_err());
+        assert!(validate_reward_percentiles(&[f64::NAN]).is_err());
+        assert_eq!(
+            validate_reward_percentiles(&[10.0, 50.0, 20.0])
+                .unwrap_err()
+                .to_string(),
+            "invalid reward percentile: #1:50 > #2:20"
+        );
+    }
+
+    #[test]
+    fn percentile_rewards_walk_gas_used() {
+        let fees = vec![
+            (U256::new(3), 21_000),
+            (U256::new(1), 50_000),
+            (U256::new(2), 29_000),
+        ];
+        assert_eq!(
+            percentile_rewards(fees, 100_000, &[0.0, 50.0, 60.0, 100.0]),
+            vec![U256::new(1), U256::new(1), U256::new(2), U256::new(3)]
+        );
+        assert_eq!(
+            percentile_rewards(vec![], 0, &[10.0, 90.0]),
+            vec![U256::ZERO, U256::ZERO]
+        );
+    }
+
+    #[test]
+    fn account_override_merge() {
+        let slot = H256::from_low_u64_be;
+        let mut account = AccountOverride {
+            balance: Some(U256::ONE),
+            state_diff: Some([(slot(1), U256::ONE)].into_iter().collect()),
+            ..Default::default()
+        };
+        assert_eq!(AccountOverride::default().storage(&slot(1)), None);
+
+        account.merge(AccountOverride {
+            nonce: Some(2),
+            state_diff: Some(
+                [(slot(1), U256::new(5)), (slot(2), U256::new(6))]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        });
+        assert_eq!(account.balance, Some(U256::ONE));
+        assert_eq!(account.nonce, Some(2));
+        assert_eq!(account.storage(&slot(1)), Some(U256::new(5)));
+        assert_eq!(account.storage(&slot(2)), Some(U256::new(6)));
+        assert_eq!(account.storage(&slot(3)), None);
+
+        // A full state replaces every slot patched before it, and later patches apply to it.
+        account.merge(AccountOverride {
+            state: Some([(slot(3), U256::new(7))].into_iter().collect()),
+            ..Default::default()
+        });
+        account.merge(AccountOverride {
+            state_diff: Some([(slot(4), U256::new(8))].into_iter().collect()),
+            ..Default::default()
+        });
+        assert!(account.state_diff.is_none());
+        assert_eq!(account.storage(&slot(1)), Some(U256::ZERO));
+        assert_eq!(account.storage(&slot(3)), Some(U256::new(7)));
+        assert_eq!(account.storage(&slot(4)), Some(U256::new(8)));
+        assert_eq!(account.balance, Some(U256::ONE));
+    }
+
+    #[test]
+    fn sandbox_overlay_answers_after_a_send() {
+        let sender = Address::repeat_byte(1);
+        let token = Address::repeat_byte(2);
+        let slot = H256::from_low_u64_be(3);
+        let message = Message::Legacy {
+            chain_id: None,
+            nonce: 0,
+            gas_price: U256::new(10),
+            gas_limit: 100_000,
+            action: TransactionAction::Call(token),
+            value: U256::ZERO,
+            input: Bytes::new(),
+        };
+        let result = |gas_used| ExecutionResult {
+            status_code: StatusCode::Success,
+            output_data: Bytes::new(),
+            gas_used,
+            logs: vec![log(token, vec![slot])],
+        };
+        let changes = |nonce, balance, value| {
+            [
+                (
+                    sender,
+                    AccountOverride {
+                        balance: Some(U256::new(balance)),
+                        nonce: Some(nonce),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    token,
+                    AccountOverride {
+                        state_diff: Some([(slot, U256::new(value))].into_iter().collect()),
+                        ..Default::default()
+                    },
+                ),
+            ]
+            .into_iter()
+            .collect()
+        };
+
+        let mut sandbox = Sandbox::new(Some(BlockNumber(7)));
+        let first = H256::repeat_byte(1);
+        let second = H256::repeat_byte(2);
+        sandbox.record(
+            first,
+            BlockNumber(8),
+            sender,
+            0,
+            &message,
+            &result(30_000),
+            changes(1, 900, 5),
+        );
+        sandbox.record(
+            second,
+            BlockNumber(8),
+            sender,
+            1,
+            &message,
+            &result(20_000),
+            changes(2, 800, 6),
+        );
+
+        // Later sends win over earlier ones; everything else is left to the database.
+        assert_eq!(sandbox.state()[&sender].balance, Some(U256::new(800)));
+        assert_eq!(sandbox.state()[&sender].nonce, Some(2));
+        assert_eq!(sandbox.state()[&token].storage(&slot), Some(U256::new(6)));
+        assert_eq!(sandbox.state()[&token].balance, None);
+        assert_eq!(
+            sandbox.state()[&token].storage(&H256::zero()),
+            None,
+            "unwritten slots are read from the database"
+        );
+        assert!(!sandbox.state().contains_key(&Address::zero()));
+
+        let receipt = sandbox.receipt(&second).unwrap();
+        assert_eq!(receipt.transaction_index, U64::from(1_u8));
+        assert_eq!(receipt.gas_used, U64::from(20_000_u64));
+        assert_eq!(receipt.cumulative_gas_used, U64::from(50_000_u64));
+        assert_eq!(receipt.status, U64::from(1_u8));
+        assert_eq!(receipt.logs[0].log_index, Some(U64::from(1_u8)));
+        assert_eq!(sandbox.transaction(&first).unwrap().nonce, U64::zero());
+        assert!(sandbox.receipt(&H256::zero()).is_none());
+
+        // Sends into a sandbox aren't bound by any block, but a pending block's are.
+        assert!(sandbox.fits(40_000, 30_000));
+        let mut pending = Sandbox::pending_block(BlockNumber(7));
+        pending.cumulative_gas_used = sandbox.cumulative_gas_used;
+        assert!(!pending.fits(70_000, 30_000));
+        assert!(pending.fits(80_000, 30_000));
+    }
+
+    #[test]
+    fn state_changed_and_changed_back_is_left_out() {
+        let account = |balance| {
+            Some(Account {
+                nonce: 1,
+                balance: U256::new(balance),
+                code_hash: EMPTY_HASH,
+            })
+        };
+        let (address, created) = (Address::repeat_byte(0x11), Address::repeat_byte(0x22));
+        let (slot, changed_slot) = (H256::repeat_byte(0x33), H256::repeat_byte(0x44));
+
+        // A -> B -> A: the change sets hold A before the first block and B before the second.
+        let mut prior = PriorState::default();
+        for (balance, value) in [(1, 1), (2, 2)] {
+            prior.record(Change::Account {
+                address,
+                account: account(balance),
+            });
+            prior.record(Change::Storage {
+                address,
+                location: slot,
+                value: U256::new(value),
+            });
+        }
+        prior.record(Change::Account {
+            address: created,
+            account: None,
+        });
+        prior.record(Change::Storage {
+            address: created,
+            location: changed_slot,
+            value: U256::ZERO,
+        });
+
+        let changes = prior
+            .net_changes(
+                |changed| Ok(account(if changed == created { 5 } else { 1 })),
+                |_, location| {
+                    Ok(if location == changed_slot {
+                        U256::new(2)
+                    } else {
+                        U256::ONE
+                    })
+                },
+            )
+            .unwrap();
+        assert_eq!(changes.keys().collect::<Vec<_>>(), vec![&created]);
+        assert_eq!(changes[&created].account, Some((None, account(5))));
+        assert_eq!(
+            changes[&created].storage,
+            [(changed_slot, (U256::ZERO, U256::new(2)))]
+                .into_iter()
+                .collect()
+        );
     }
 }
@@ -0,0 +1,711 @@
+use akula::{
+    execution::{
+        analysis_cache::AnalysisCache,
+        evm::{AnalyzedCode, StatusCode},
+        evmglue,
+        tracer::Tracer,
+    },
+    models::*,
+    IntraBlockState, State,
+};
+use anyhow::format_err;
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use thiserror::Error;
+
+use crate::db_wrapper::StateOverride;
+
+/// Number of analyses the cache of a single execution, or of a batch of them, holds.
+const LOCAL_ANALYSIS_CACHE_SIZE: usize = 256;
+
+/// Lookups of the jump destination analysis of the code calls and transactions enter at
+/// the top level, in the cache of their execution or else in the shared cache.
+///
+/// Only calls and transactions executed on their own are counted, not the contracts they
+/// call into nor transactions replayed as part of a block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AnalysisCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Jump destination analyses shared by the executions on a `DbWrapper`: one LRU cache of
+/// up to `capacity` analyses keyed by code hash.
+///
+/// The interpreter needs a cache of its own while it runs, so every execution works with a
+/// `LocalAnalysisCache` primed from this one with the code it enters at the top level.
+/// Contracts called from there are analyzed in the local cache only.
+pub struct SharedAnalysisCache {
+    capacity: usize,
+    cache: Mutex<AnalysisCache>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl SharedAnalysisCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            cache: Mutex::new(AnalysisCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns a cache for one execution, or for several run one after another.
+    pub fn local(&self) -> LocalAnalysisCache<'_> {
+        LocalAnalysisCache {
+            shared: self,
+            cache: AnalysisCache::new(LOCAL_ANALYSIS_CACHE_SIZE),
+        }
+    }
+
+    /// Returns the analysis of the code hashing to `code_hash`, analyzing the code `code`
+    /// reads and caching the analysis on a miss.
+    fn analysis(
+        &self,
+        code_hash: H256,
+        code: impl FnOnce() -> anyhow::Result<Bytes>,
+    ) -> anyhow::Result<Arc<AnalyzedCode>> {
+        let cached = self
+            .cache
+            .lock()
+            .map_err(|_| format_err!("analysis cache lock poisoned"))?
+            .get(code_hash)
+            .cloned();
+        if let Some(analysis) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(analysis);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // Analyze outside of the lock; concurrent misses on the same code each analyze it.
+        let analysis = Arc::new(AnalyzedCode::analyze(&code()?));
+        self.cache
+            .lock()
+            .map_err(|_| format_err!("analysis cache lock poisoned"))?
+            .put(code_hash, analysis.clone());
+        Ok(analysis)
+    }
+
+    pub fn stats(&self) -> AnalysisCacheStats {
+        AnalysisCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Debug for SharedAnalysisCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedAnalysisCache")
+            .field("capacity", &self.capacity)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// The cache an execution's interpreter works with, primed from a `SharedAnalysisCache`.
+pub struct LocalAnalysisCache<'a> {
+    shared: &'a SharedAnalysisCache,
+    cache: AnalysisCache,
+}
+
+impl LocalAnalysisCache<'_> {
+    /// Makes sure this cache has the analysis of the code hashing to `code_hash`, taking
+    /// it from the shared cache, and counts the lookup towards the shared cache's stats.
+    pub fn prime(
+        &mut self,
+        code_hash: H256,
+        code: impl FnOnce() -> anyhow::Result<Bytes>,
+    ) -> anyhow::Result<()> {
+        if self.cache.get(code_hash).is_some() {
+            self.shared.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        let analysis = self.shared.analysis(code_hash, code)?;
+        self.cache.put(code_hash, analysis);
+        Ok(())
+    }
+}
+
+impl Deref for LocalAnalysisCache<'_> {
+    type Target = AnalysisCache;
+
+    fn deref(&self) -> &AnalysisCache {
+        &self.cache
+    }
+}
+
+impl DerefMut for LocalAnalysisCache<'_> {
+    fn deref_mut(&mut self) -> &mut AnalysisCache {
+        &mut self.cache
+    }
+}
+
+/// Primes `analysis_cache` with the code `message` enters at the top level.
+pub fn prime_code_analysis<S: State>(
+    state: &mut IntraBlockState<'_, S>,
+    analysis_cache: &mut LocalAnalysisCache<'_>,
+    message: &Message,
+) -> anyhow::Result<()> {
+    if let TransactionAction::Call(to) = message.action() {
+        let code_hash = state.get_code_hash(to)?;
+        if code_hash != EMPTY_HASH {
+            analysis_cache.prime(code_hash, || Ok(state.get_code(to)?.unwrap_or_default()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Outcome of a message executed as a top-level transaction.
+#[derive(Clone, Debug)]
+pub struct ExecutionResult {
+    pub status_code: StatusCode,
+    pub output_data: Bytes,
+    /// Gas used including intrinsic gas, after refunds.
+    pub gas_used: u64,
+    /// Logs in the state's substate once the message has executed.
+    pub logs: Vec<Log>,
+}
+
+/// Executes `message` the way a transaction would be, on top of `state`: intrinsic gas
+/// is charged, EIP-2929 warm addresses and the access list are pre-accessed, the sender
+/// nonce is bumped and refunds are applied. Fees are not charged.
+pub fn execute_message<S: State>(
+    state: &mut IntraBlockState<'_, S>,
+    tracer: &mut dyn Tracer,
+    analysis_cache: &mut LocalAnalysisCache<'_>,
+    header: &PartialHeader,
+    block_spec: &BlockExecutionSpec,
+    message: &Message,
+    sender: Address,
+) -> anyhow::Result<ExecutionResult> {
+    let revision = block_spec.revision;
+
+    let intrinsic_gas = intrinsic_gas(message, revision);
+    let gas = message
+        .gas_limit()
+        .checked_sub(intrinsic_gas)
+        .ok_or_else(|| {
+            format_err!(
+                "intrinsic gas too low: have {}, want {intrinsic_gas}",
+                message.gas_limit()
+            )
+        })?;
+
+    if revision >= Revision::Berlin {
+        state.access_account(sender);
+        if let TransactionAction::Call(to) = message.action() {
+            state.access_account(to);
+        }
+        for address in precompiles(revision) {
+            state.access_account(address);
+        }
+        for item in access_list(message) {
+            state.access_account(item.address);
+            for slot in &item.slots {
+                state.access_storage(item.address, U256::from_be_bytes(slot.0));
+            }
+        }
+    }
+
+    prime_code_analysis(state, analysis_cache, message)?;
+
+    // Contract creation bumps the nonce itself while deriving the new address.
+    if let TransactionAction::Call(_) = message.action() {
+        let nonce = state.get_nonce(sender)?;
+        state.set_nonce(sender, nonce + 1)?;
+    }
+
+    let result = evmglue::execute(
+        state,
+        tracer,
+        analysis_cache,
+        header,
+        block_spec,
+        message,
+        sender,
+        gas,
+    )?;
+
+    let gas_used = message.gas_limit() - result.gas_left as u64;
+    let mut refund = state.get_refund();
+    if revision < Revision::London {
+        refund += state.number_of_self_destructs() as u64 * 24_000;
+    }
+    let max_refund_quotient = if revision >= Revision::London { 5 } else { 2 };
+
+    Ok(ExecutionResult {
+        status_code: result.status_code,
+        output_data: result.output_data,
+        gas_used: gas_used - refund.min(gas_used / max_refund_quotient),
+        logs: state.logs().to_vec(),
+    })
+}
+
+/// Why a transaction can't be executed on top of a state, worded like geth's errors.
+#[derive(Error, Clone, Debug)]
+pub enum InvalidTransaction {
+    #[error("nonce too low: address {sender}, tx: {nonce} state: {expected}")]
+    NonceTooLow {
+        sender: Address,
+        nonce: u64,
+        expected: u64,
+    },
+    #[error("nonce too high: address {sender}, tx: {nonce} state: {expected}")]
+    NonceTooHigh {
+        sender: Address,
+        nonce: u64,
+        expected: u64,
+    },
+    /// The block has less gas left than the transaction's gas limit.
+    #[error("gas limit reached")]
+    GasLimitReached,
+    #[error(
+        "max fee per gas less than block base fee: address {sender}, \
+         maxFeePerGas: {max_fee_per_gas} baseFee: {base_fee_per_gas}"
+    )]
+    FeeCapTooLow {
+        sender: Address,
+        max_fee_per_gas: U256,
+        base_fee_per_gas: U256,
+    },
+    #[error(
+        "insufficient funds for gas * price + value: address {sender} have {balance} want {cost}"
+    )]
+    InsufficientFunds {
+        sender: Address,
+        balance: U256,
+        cost: U256,
+    },
+}
+
+/// Checks that `message` pays at least the block's base fee, if it has one, and that
+/// `balance` covers its value and its whole gas limit at its maximum fee, as geth does
+/// before buying gas.
+pub fn validate_fees(
+    message: &Message,
+    sender: Address,
+    balance: U256,
+    base_fee_per_gas: Option<U256>,
+) -> Result<(), InvalidTransaction> {
+    let max_fee_per_gas = message.max_fee_per_gas();
+    if let Some(base_fee_per_gas) = base_fee_per_gas {
+        if max_fee_per_gas < base_fee_per_gas {
+            return Err(InvalidTransaction::FeeCapTooLow {
+                sender,
+                max_fee_per_gas,
+                base_fee_per_gas,
+            });
+        }
+    }
+
+    let cost = U256::from(message.gas_limit())
+        .saturating_mul(max_fee_per_gas)
+        .saturating_add(message.value());
+    if balance < cost {
+        return Err(InvalidTransaction::InsufficientFunds {
+            sender,
+            balance,
+            cost,
+        });
+    }
+
+    Ok(())
+}
+
+/// Checks that `sender` can send `message` on top of `state`: that `nonce`, if given, is
+/// the sender's next nonce, and that its fees pass `validate_fees`.
+pub fn validate_transaction<S: State>(
+    state: &mut IntraBlockState<'_, S>,
+    header: &PartialHeader,
+    message: &Message,
+    sender: Address,
+    nonce: Option<u64>,
+) -> anyhow::Result<Result<(), InvalidTransaction>> {
+    if let Some(nonce) = nonce {
+        let expected = state.get_nonce(sender)?;
+        match nonce.cmp(&expected) {
+            std::cmp::Ordering::Less => {
+                return Ok(Err(InvalidTransaction::NonceTooLow {
+                    sender,
+                    nonce,
+                    expected,
+                }))
+            }
+            std::cmp::Ordering::Greater => {
+                return Ok(Err(InvalidTransaction::NonceTooHigh {
+                    sender,
+                    nonce,
+                    expected,
+                }))
+            }
+            std::cmp::Ordering::Equal => {}
+        }
+    }
+
+    let balance = state.get_balance(sender)?;
+    Ok(validate_fees(
+        message,
+        sender,
+        balance,
+        header.base_fee_per_gas,
+    ))
+}
+
+/// Executes `message` as a transaction of a block with the given header: on top of what
+/// `execute_message` does, the sender pays for gas, the beneficiary collects the priority
+/// fee and the transaction's substate is finalized, as `ExecutionProcessor` does.
+pub fn execute_transaction<S: State>(
+    state: &mut IntraBlockState<'_, S>,
+    tracer: &mut dyn Tracer,
+    analysis_cache: &mut LocalAnalysisCache<'_>,
+    header: &PartialHeader,
+    block_spec: &BlockExecutionSpec,
+    message: &Message,
+    sender: Address,
+) -> anyhow::Result<ExecutionResult> {
+    let base_fee_per_gas = header.base_fee_per_gas.unwrap_or(U256::ZERO);
+    let gas_price = effective_gas_price(message, base_fee_per_gas);
+
+    validate_transaction(state, header, message, sender, None)??;
+    state.clear_journal_and_substate();
+    state.subtract_from_balance(sender, U256::from(message.gas_limit()) * gas_price)?;
+
+    let result = execute_message(
+        state,
+        tracer,
+        analysis_cache,
+        header,
+        block_spec,
+        message,
+        sender,
+    )?;
+
+    state.add_to_balance(
+        sender,
+        U256::from(message.gas_limit() - result.gas_used) * gas_price,
+    )?;
+    state.add_to_balance(
+        header.beneficiary,
+        U256::from(result.gas_used) * gas_price.saturating_sub(base_fee_per_gas),
+    )?;
+
+    state.destruct_selfdestructs();
+    if block_spec.revision >= Revision::Spurious {
+        state.destruct_touched_dead();
+    }
+    state.finalize_transaction();
+
+    Ok(result)
+}
+
+/// Price per gas the sender pays, of which everything above the base fee goes to the
+/// block's beneficiary.
+pub fn effective_gas_price(message: &Message, base_fee_per_gas: U256) -> U256 {
+    message
+        .max_fee_per_gas()
+        .min(base_fee_per_gas + message.max_priority_fee_per_gas())
+}
+
+/// Applies state overrides on top of `state` before a simulated call.
+pub fn apply_state_override<S: State>(
+    state: &mut IntraBlockState<'_, S>,
+    state_override: &StateOverride,
+) -> anyhow::Result<()> {
+    for (address, account_override) in state_override {
+        let address = *address;
+
+        if let Some(storage) = &account_override.state {
+            // Recreating the account is the only way to drop all of its storage; keep
+            // everything else as it was.
+            let nonce = state.get_nonce(address)?;
+            let code = state.get_code(address)?;
+            state.create_contract(address)?;
+            state.set_nonce(address, nonce)?;
+            if let Some(code) = code {
+                state.set_code(address, code)?;
+            }
+            for (location, value) in storage {
+                state.set_storage(address, U256::from_be_bytes(location.0), *value)?;
+            }
+        }
+        if let Some(storage) = &account_override.state_diff {
+            for (location, value) in storage {
+                state.set_storage(address, U256::from_be_bytes(location.0), *value)?;
+            }
+        }
+        if let Some(balance) = account_override.balance {
+            state.set_balance(address, balance)?;
+        }
+        if let Some(nonce) = account_override.nonce {
+            state.set_nonce(address, nonce)?;
+        }
+        if let Some(code) = &account_override.code {
+            state.set_code(address, code.clone())?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn intrinsic_gas(message: &Message, revision: Revision) -> u64 {
+    let mut gas = if let TransactionAction::Create = message.action() {
+        if revision >= Revision::Homestead {
+            53_000
+        } else {
+            21_000
+        }
+    } else {
+        21_000
+    };
+
+    let zero_bytes = message.input().iter().filter(|byte| **byte == 0).count() as u64;
+    let non_zero_bytes = message.input().len() as u64 - zero_bytes;
+    let non_zero_byte_cost = if revision >= Revision::Istanbul {
+        16
+    } else {
+        68
+    };
+    gas += zero_bytes * 4 + non_zero_bytes * non_zero_byte_cost;
+
+    for item in access_list(message) {
+        gas += 2_400 + 1_900 * item.slots.len() as u64;
+    }
+
+    gas
+}
+
+pub fn access_list(message: &Message) -> &[AccessListItem] {
+    match message {
+        Message::Legacy { .. } => &[],
+        Message::EIP2930 { access_list, .. } | Message::EIP1559 { access_list, .. } => access_list,
+    }
+}
+
+/// Returns `message` with its access list replaced, turning legacy messages into EIP-2930 ones.
+pub fn with_access_list(
+    message: &Message,
+    chain_id: ChainId,
+    access_list: Vec<AccessListItem>,
+) -> Message {
+    match message.clone() {
+        Message::Legacy {
+            chain_id: legacy_chain_id,
+            nonce,
+            gas_price,
+            gas_limit,
+            action,
+            value,
+            input,
+        } => Message::EIP2930 {
+            chain_id: legacy_chain_id.unwrap_or(chain_id),
+            nonce,
+            gas_price,
+            gas_limit,
+            action,
+            value,
+            input,
+            access_list,
+        },
+        Message::EIP2930 {
+            chain_id,
+            nonce,
+            gas_price,
+            gas_limit,
+            action,
+            value,
+            input,
+            ..
+        } => Message::EIP2930 {
+            chain_id,
+            nonce,
+            gas_price,
+            gas_limit,
+            action,
+            value,
+            input,
+            access_list,
+        },
+        Message::EIP1559 {
+            chain_id,
+            nonce,
+            max_priority_fee_per_gas,
+            max_fee_per_gas,
+            gas_limit,
+            action,
+            value,
+            input,
+            ..
+        } => Message::EIP1559 {
+            chain_id,
+            nonce,
+            max_priority_fee_per_gas,
+            max_fee_per_gas,
+            gas_limit,
+            action,
+            value,
+            input,
+            access_list,
+        },
+    }
+}
+
+/// Copy of `message` with its nonce replaced.
+pub fn with_nonce(message: &Message, nonce: u64) -> Message {
+    let mut message = message.clone();
+    match &mut message {
+        Message::Legacy { nonce: n, .. }
+        | Message::EIP2930 { nonce: n, .. }
+        | Message::EIP1559 { nonce: n, .. } => *n = nonce,
+    }
+    message
+}
+
+/// Returns `message` with its gas limit replaced.
+pub fn with_gas_limit(message: &Message, gas_limit: u64) -> Message {
+    let mut message = message.clone();
+    match &mut message {
+        Message::Legacy {
+            gas_limit: limit, ..
+        }
+        | Message::EIP2930 {
+            gas_limit: limit, ..
+        }
+        | Message::EIP1559 {
+            gas_limit: limit, ..
+        } => *limit = gas_limit,
+    }
+    message
+}
+
+/// Addresses of the precompiled contracts active in `revision`.
+pub fn precompiles(revision: Revision) -> impl Iterator<Item = Address> {
+    let count = if revision >= Revision::Istanbul {
+        9
+    } else if revision >= Revision::Byzantium {
+        8
+    } else {
+        4
+    };
+    (1..=count).map(Address::from_low_u64_be)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(action: TransactionAction, input: &[u8]) -> Message {
+        Message::Legacy {
+            chain_id: None,
+            nonce: 0,
+            gas_price: U256::ZERO,
+            gas_limit: 100_000,
+            action,
+            value: U256::ZERO,
+            input: input.to_vec().into(),
+        }
+    }
+
+    fn eip1559(max_fee_per_gas: u64, value: u64) -> Message {
+        Message::EIP1559 {
+            chain_id: ChainId(1),
+            nonce: 0,
+            max_priority_fee_per_gas: U256::ONE,
+            max_fee_per_gas: U256::from(max_fee_per_gas),
+            gas_limit: 21_000,
+            action: TransactionAction::Call(Address::zero()),
+            value: U256::from(value),
+            input: Vec::new().into(),
+            access_list: vec![],
+        }
+    }
+
+    #[test]
+    fn fees_cover_value_and_base_fee() {
+        let sender = Address::repeat_byte(1);
+        let message = eip1559(10, 1_000);
+        let cost = U256::from(21_000 * 10 + 1_000_u64);
+
+        assert!(validate_fees(&message, sender, cost, Some(U256::from(10_u8))).is_ok());
+        assert!(matches!(
+            validate_fees(&message, sender, cost - U256::ONE, Some(U256::from(10_u8))),
+            Err(InvalidTransaction::InsufficientFunds { cost: want, .. }) if want == cost
+        ));
+        assert!(matches!(
+            validate_fees(&message, sender, cost, Some(U256::from(11_u8))),
+            Err(InvalidTransaction::FeeCapTooLow { .. })
+        ));
+        // Blocks before London have no base fee to pay.
+        assert!(validate_fees(&eip1559(0, 0), sender, U256::ZERO, None).is_ok());
+    }
+
+    #[test]
+    fn analysis_cache_hits_on_the_second_call() {
+        let shared = SharedAnalysisCache::new(10);
+        let code = Bytes::from(vec![0x60, 0x03, 0x56, 0x5b, 0x00]);
+        let code_hash = H256::repeat_byte(0xc0);
+
+        for _ in 0..2 {
+            let mut local = shared.local();
+            local.prime(code_hash, || Ok(code.clone())).unwrap();
+            assert!(local.get(code_hash).is_some());
+        }
+        assert_eq!(shared.stats(), AnalysisCacheStats { hits: 1, misses: 1 });
+
+        // A hit doesn't read the code, and a batch's own cache answers its later calls.
+        let mut local = shared.local();
+        for _ in 0..2 {
+            local
+                .prime(code_hash, || Err(format_err!("code read on a hit")))
+                .unwrap();
+        }
+        assert_eq!(shared.stats(), AnalysisCacheStats { hits: 3, misses: 1 });
+    }
+
+    #[test]
+    fn intrinsic_gas_of_calls() {
+        let call = message(TransactionAction::Call(Address::zero()), &[0, 1, 0, 2]);
+        assert_eq!(
+            intrinsic_gas(&call, Revision::Istanbul),
+            21_000 + 2 * 4 + 2 * 16
+        );
+        assert_eq!(
+            intrinsic_gas(&call, Revision::Byzantium),
+            21_000 + 2 * 4 + 2 * 68
+        );
+
+        let call = with_access_list(
+            &call,
+            ChainId(1),
+            vec![
+                AccessListItem {
+                    address: Address::zero(),
+                    slots: vec![H256::zero(), H256::repeat_byte(1)],
+                },
+                AccessListItem {
+                    address: Address::repeat_byte(1),
+                    slots: vec![H256::zero()],
+                },
+            ],
+        );
+        assert_eq!(
+            intrinsic_gas(&call, Revision::Berlin),
+            21_000 + 2 * 4 + 2 * 16 + 2 * 2_400 + 3 * 1_900
+        );
+    }
+
+    #[test]
+    fn intrinsic_gas_of_creations() {
+        let create = message(TransactionAction::Create, &[]);
+        assert_eq!(intrinsic_gas(&create, Revision::Frontier), 21_000);
+        assert_eq!(intrinsic_gas(&create, Revision::Homestead), 53_000);
+    }
+}
@@ -1,6 +1,15 @@
 mod db_wrapper;
+mod executor;
 mod middleware;
+mod pool;
+mod proof;
+mod tracer;
 mod utils;
 
-pub use middleware::{AkulaMiddleware, AkulaMiddlewareError};
+pub use executor::AnalysisCacheStats;
+pub use middleware::{
+    AccountOverride, AkulaMiddleware, AkulaMiddlewareError, BlockOverride, DumpAccount,
+    ExecutionError, GasOracleConfig, PendingBlockConfig, RequestTimeouts, RevertReason,
+    SimulatedTransaction, StateOverride, StorageRange,
+};
 pub use utils::open_database;
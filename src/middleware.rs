@@ -1,15 +1,30 @@
 use akula::kv::{mdbx::*, MdbxWithDirHandle};
 use async_trait::async_trait;
 use ethers::{
-    providers::{FromErr, Middleware},
+    providers::{FromErr, Middleware, ProviderError},
     types::{transaction::eip2718::TypedTransaction, *},
 };
-use std::sync::Arc;
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 pub use ethereum_jsonrpc::types as jsonrpc;
 
-use crate::{db_wrapper::DbWrapper, utils};
+use crate::{
+    db_wrapper::{self, DbWrapper, GasEstimate, LogFilterBlocks, Sandbox},
+    executor::AnalysisCacheStats,
+    pool::{BlockingPool, Cancellation},
+    utils,
+};
+
+/// Accounts a state dump reads ahead of its consumer.
+const STATE_DUMP_BUFFER: usize = 1024;
 
 #[derive(Error, Debug)]
 pub enum AkulaMiddlewareError<M: Middleware> {
@@ -19,6 +34,15 @@ pub enum AkulaMiddlewareError<M: Middleware> {
     /// A type conversion error has occured.
     #[error("failed to convert Akula type to ethers-rs type: {0}")]
     ConversionError(String),
+    /// The request can't be served from Akula's database.
+    #[error("unsupported request: {0}")]
+    Unsupported(String),
+    /// Writing an export has failed.
+    #[error(transparent)]
+    WriteError(#[from] std::io::Error),
+    /// The call did not execute successfully.
+    #[error(transparent)]
+    ExecutionError(ExecutionError),
     /// An error has occured in one of the middlewares.
     #[error("{0}")]
     MiddlewareError(M::Error),
@@ -30,13 +54,182 @@ impl<M: Middleware> FromErr<M::Error> for AkulaMiddlewareError<M> {
     }
 }
 
+/// Decoded payload of a revert raised by Solidity.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RevertReason {
+    /// `revert("...")` and failed `require`s.
+    Error(String),
+    /// Failed `assert`s, arithmetic overflows and other runtime panics.
+    Panic(U256),
+}
+
+impl std::fmt::Display for RevertReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RevertReason::Error(message) => write!(f, "{message}"),
+            RevertReason::Panic(code) => write!(f, "panic code {code:#x}"),
+        }
+    }
+}
+
+/// Why a call did not execute successfully, as remote providers report it.
+#[derive(Error, Clone, Debug)]
+pub enum ExecutionError {
+    #[error(
+        "execution reverted{}",
+        .reason.as_ref().map_or(String::new(), |reason| format!(": {reason}"))
+    )]
+    Reverted {
+        /// Raw revert data.
+        data: Bytes,
+        reason: Option<RevertReason>,
+    },
+    #[error("out of gas")]
+    OutOfGas,
+    /// An undefined opcode; the designated invalid instruction (0xFE) halts instead.
+    #[error("invalid opcode")]
+    InvalidOpcode,
+    /// Any other exceptional halt.
+    #[error("{0}")]
+    Halted(String),
+    /// The transaction could not be executed at all, like when its nonce is wrong.
+    #[error("{0}")]
+    Invalid(String),
+}
+
+/// Parameters of the local gas price oracle.
+#[derive(Clone, Copy, Debug)]
+pub struct GasOracleConfig {
+    /// Number of most recent blocks to sample priority fees from.
+    pub blocks: u64,
+    /// Percentile of the sampled priority fees to suggest.
+    pub percentile: f64,
+}
+
+impl Default for GasOracleConfig {
+    fn default() -> Self {
+        Self {
+            blocks: 20,
+            percentile: 60.0,
+        }
+    }
+}
+
+/// How the middleware builds the pending block from the inner provider's txpool.
+#[derive(Clone, Copy, Debug)]
+pub struct PendingBlockConfig {
+    /// How long a read of the txpool is reused before the txpool is read again.
+    pub txpool_ttl: Duration,
+    /// Serve the latest block as the pending block if the inner provider can't serve its
+    /// txpool, instead of failing.
+    pub latest_without_txpool: bool,
+}
+
+impl Default for PendingBlockConfig {
+    fn default() -> Self {
+        Self {
+            txpool_ttl: Duration::from_secs(1),
+            latest_without_txpool: false,
+        }
+    }
+}
+
+/// How long requests of each class may take, queueing on the blocking pool included,
+/// before they fail. `None` lets them run until they finish.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestTimeouts {
+    /// Database reads: accounts, blocks, transactions, proofs and histories.
+    pub read: Option<Duration>,
+    /// Calls and transactions executed on top of a block, and the pending block.
+    pub execution: Option<Duration>,
+    /// Replays of whole blocks: traces, receipts, logs, fee history, and batches of calls.
+    /// These are also told to stop between transactions once they time out.
+    pub trace: Option<Duration>,
+}
+
+/// Pending block last built, with what it was built from.
+#[derive(Debug)]
+struct PendingBlock {
+    parent: u64,
+    transactions: Vec<TxHash>,
+    sandbox: Arc<Sandbox>,
+}
+
+/// Replacement state of an account for `call_with_overrides` and
+/// `estimate_gas_with_overrides`, as in geth's `eth_call` state overrides.
+#[derive(Clone, Debug, Default)]
+pub struct AccountOverride {
+    pub balance: Option<U256>,
+    pub nonce: Option<U64>,
+    pub code: Option<Bytes>,
+    /// Replaces the whole storage of the account.
+    pub state: Option<HashMap<H256, H256>>,
+    /// Patches individual storage slots.
+    pub state_diff: Option<HashMap<H256, H256>>,
+}
+
+pub type StateOverride = HashMap<Address, AccountOverride>;
+
+/// Header fields to replace in `call_with_overrides`, to simulate a call as if it ran in
+/// a later block.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BlockOverride {
+    pub number: Option<U64>,
+    pub timestamp: Option<U64>,
+    pub base_fee_per_gas: Option<U256>,
+    pub coinbase: Option<Address>,
+    pub gas_limit: Option<U64>,
+    pub difficulty: Option<U256>,
+    pub prevrandao: Option<H256>,
+}
+
+/// Outcome of one transaction of a bundle run by `simulate_bundle`.
+#[derive(Clone, Debug)]
+pub struct SimulatedTransaction {
+    pub success: bool,
+    /// Return data, or revert data if the transaction failed.
+    pub output: Bytes,
+    pub gas_used: U256,
+    pub logs: Vec<Log>,
+    pub error: Option<ExecutionError>,
+}
+
+/// Page of an account's non-zero storage slots returned by `storage_range`.
+#[derive(Clone, Debug, Default)]
+pub struct StorageRange {
+    pub storage: BTreeMap<H256, H256>,
+    /// Slot the next page starts at, if there are more slots.
+    pub next_key: Option<H256>,
+}
+
+/// Account streamed by `dump_state`, serialized like in geth's state dumps.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DumpAccount {
+    pub address: Address,
+    pub balance: U256,
+    pub nonce: U64,
+    pub code_hash: H256,
+    pub code: Bytes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage: Option<BTreeMap<H256, H256>>,
+}
+
 #[derive(Debug)]
 pub struct AkulaMiddleware<M, DB>
 where
     DB: EnvironmentKind,
 {
     inner: M,
-    db_wrapper: DbWrapper<DB>,
+    db_wrapper: Arc<DbWrapper<DB>>,
+    gas_oracle: GasOracleConfig,
+    sandbox: Option<Arc<Mutex<Sandbox>>>,
+    pending_config: PendingBlockConfig,
+    /// Last read of the txpool, `None` if it couldn't be read and that is tolerated.
+    txpool: Mutex<Option<(Instant, Option<Arc<TxpoolContent>>)>>,
+    pending: Mutex<Option<PendingBlock>>,
+    pool: BlockingPool,
+    timeouts: RequestTimeouts,
 }
 
 impl<M, DB> AkulaMiddleware<M, DB>
@@ -47,8 +240,710 @@ where
     pub fn new(inner: M, db: Arc<MdbxWithDirHandle<DB>>) -> Self {
         Self {
             inner,
-            db_wrapper: DbWrapper::new(db, 100_000_000),
+            db_wrapper: Arc::new(DbWrapper::new(db, 100_000_000)),
+            gas_oracle: GasOracleConfig::default(),
+            sandbox: None,
+            pending_config: PendingBlockConfig::default(),
+            txpool: Mutex::new(None),
+            pending: Mutex::new(None),
+            pool: BlockingPool::new(std::thread::available_parallelism().map_or(1, |n| n.get())),
+            timeouts: RequestTimeouts::default(),
+        }
+    }
+
+    /// Executes the call on top of the block with the given accounts' state and header
+    /// fields replaced.
+    pub async fn call_with_overrides(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+        state_override: &StateOverride,
+        block_override: &BlockOverride,
+    ) -> Result<Bytes, AkulaMiddlewareError<M>> {
+        let block_id = block.map_or(
+            jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest),
+            |block_id| utils::ethers_block_id_to_akula(block_id),
+        );
+        let message_call = utils::ethers_typed_tx_to_message_call(tx)?;
+        let mut state_override = utils::ethers_state_override_to_akula(state_override);
+        let block_id = match self
+            .state_view(block, move |sandbox| sandbox.state().clone())
+            .await?
+        {
+            Some((base, mut overlay)) => {
+                db_wrapper::merge_state_override(&mut overlay, state_override);
+                state_override = overlay;
+                base
+            }
+            None => block_id,
+        };
+
+        let block_override = utils::ethers_block_override_to_akula(block_override);
+
+        self.executing(move |db| db.call(message_call, block_id, &state_override, &block_override))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |(status_code, output)| match utils::execution_error(status_code, &output.0) {
+                    Some(error) => Err(AkulaMiddlewareError::ExecutionError(error)),
+                    None => Ok(Bytes::from(output.0)),
+                },
+            )
+    }
+
+    /// Executes the calls in parallel, like a `Multicall` contract would but without
+    /// deploying one. Calls see the database only, not the sandbox, and calls against the
+    /// pending block are refused.
+    ///
+    /// The blocks the calls target, the latest one included, are resolved once each in a
+    /// single read transaction, along with the chain specification. The calls are then split
+    /// into one batch per blocking pool thread, each reading state at the resolved blocks
+    /// through its own read transaction.
+    pub async fn call_many(
+        &self,
+        calls: Vec<(TypedTransaction, Option<BlockId>)>,
+    ) -> Result<Vec<Result<Bytes, AkulaMiddlewareError<M>>>, AkulaMiddlewareError<M>> {
+        let (message_calls, block_ids): (Vec<_>, Vec<_>) = calls
+            .iter()
+            .map(|(tx, block)| {
+                let block_id = utils::settled_block_id(block.map_or(
+                    jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest),
+                    |block_id| utils::ethers_block_id_to_akula(block_id),
+                ))?;
+                Ok((utils::ethers_typed_tx_to_message_call(tx)?, block_id))
+            })
+            .collect::<Result<Vec<_>, AkulaMiddlewareError<M>>>()?
+            .into_iter()
+            .unzip();
+
+        let (chain_spec, blocks) = self
+            .blocking(move |db| db.resolve_call_blocks(block_ids))
+            .await?;
+
+        let mut results = Vec::with_capacity(message_calls.len());
+        let mut resolved = Vec::new();
+        for (call, block) in message_calls.into_iter().zip(blocks) {
+            match block {
+                Ok(block) => {
+                    resolved.push((results.len(), (call, block)));
+                    results.push(None);
+                }
+                Err(e) => results.push(Some(Err(AkulaMiddlewareError::DbWrapperError(e)))),
+            }
+        }
+
+        let batch_size = ((resolved.len() + self.pool.threads() - 1) / self.pool.threads()).max(1);
+        let mut resolved = resolved.into_iter().peekable();
+        let mut batches = Vec::new();
+        while resolved.peek().is_some() {
+            let (indices, batch): (Vec<_>, Vec<_>) = resolved.by_ref().take(batch_size).unzip();
+            let chain_spec = chain_spec.clone();
+            let batch = self.tracing(move |db, cancel| db.call_many(&chain_spec, batch, cancel));
+            batches
+                .push(async move { Ok::<_, anyhow::Error>(indices.into_iter().zip(batch.await?)) });
+        }
+
+        for (index, result) in futures::future::try_join_all(batches)
+            .await?
+            .into_iter()
+            .flatten()
+        {
+            results[index] = Some(match result {
+                Ok((status_code, output)) => match utils::execution_error(status_code, &output.0) {
+                    Some(error) => Err(AkulaMiddlewareError::ExecutionError(error)),
+                    None => Ok(Bytes::from(output.0)),
+                },
+                Err(e) => Err(AkulaMiddlewareError::DbWrapperError(e)),
+            });
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|result| result.expect("every call has a result"))
+            .collect())
+    }
+
+    /// Executes the transactions in order on top of the block, each one seeing the state
+    /// left by the previous ones, like `eth_callBundle`. A transaction whose nonce, if set,
+    /// isn't its sender's next one or whose sender can't pay for gas is reported as failed
+    /// without changing the state.
+    pub async fn simulate_bundle(
+        &self,
+        txs: &[TypedTransaction],
+        block: Option<BlockId>,
+        state_override: &StateOverride,
+        block_override: &BlockOverride,
+    ) -> Result<Vec<SimulatedTransaction>, AkulaMiddlewareError<M>> {
+        let message_calls = txs
+            .iter()
+            .map(|tx| {
+                Ok((
+                    utils::ethers_typed_tx_to_message_call::<M>(tx)?,
+                    tx.nonce().map(|nonce| nonce.as_u64()),
+                ))
+            })
+            .collect::<Result<Vec<_>, AkulaMiddlewareError<M>>>()?;
+
+        let mut state_override = utils::ethers_state_override_to_akula(state_override);
+        let block_id = match self
+            .state_view(block, move |sandbox| sandbox.state().clone())
+            .await?
+        {
+            Some((base, mut overlay)) => {
+                db_wrapper::merge_state_override(&mut overlay, state_override);
+                state_override = overlay;
+                base
+            }
+            None => block.map_or(
+                jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest),
+                |block_id| utils::ethers_block_id_to_akula(block_id),
+            ),
+        };
+        let block_override = utils::ethers_block_override_to_akula(block_override);
+
+        self.executing(move |db| {
+            db.simulate_bundle(message_calls, block_id, &state_override, &block_override)
+        })
+        .await
+        .map_or_else(
+            |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+            |v| Ok(v.into_iter().map(utils::bundle_result_to_ethers).collect()),
+        )
+    }
+
+    /// Estimates the gas of the transaction on top of the block with the given accounts'
+    /// state replaced.
+    pub async fn estimate_gas_with_overrides(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+        state_override: &StateOverride,
+    ) -> Result<U256, AkulaMiddlewareError<M>> {
+        let block_id = block.map_or(
+            jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest),
+            |block_id| utils::ethers_block_id_to_akula(block_id),
+        );
+        let message_call = utils::ethers_typed_tx_to_message_call(tx)?;
+        let mut state_override = utils::ethers_state_override_to_akula(state_override);
+        let block_id = match self
+            .state_view(block, move |sandbox| sandbox.state().clone())
+            .await?
+        {
+            Some((base, mut overlay)) => {
+                db_wrapper::merge_state_override(&mut overlay, state_override);
+                state_override = overlay;
+                base
+            }
+            None => block_id,
+        };
+
+        self.executing(move |db| db.estimate_gas(message_call, block_id, &state_override))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| match v {
+                    GasEstimate::Gas(gas) => Ok(U256::from(gas)),
+                    GasEstimate::Failed {
+                        status_code,
+                        output,
+                    } => Err(AkulaMiddlewareError::ExecutionError(
+                        utils::execution_error(status_code, &output)
+                            .unwrap_or(ExecutionError::OutOfGas),
+                    )),
+                },
+            )
+    }
+
+    /// Executes the call on top of the block and returns its geth-style struct logs, like
+    /// `debug_traceCall`.
+    pub async fn debug_trace_call<T: Into<TypedTransaction> + Send + Sync>(
+        &self,
+        req: T,
+        block: Option<BlockId>,
+        trace_options: GethDebugTracingOptions,
+    ) -> Result<GethTrace, AkulaMiddlewareError<M>> {
+        let block_id = utils::settled_block_id(block.map_or(
+            jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest),
+            |block_id| utils::ethers_block_id_to_akula(block_id),
+        ))?;
+        let message_call = utils::ethers_typed_tx_to_message_call(&req.into())?;
+        let config = utils::ethers_tracing_options_to_struct_logger_config(&trace_options);
+
+        self.executing(move |db| db.debug_trace_call(message_call, block_id, config))
+            .await
+            .map_err(AkulaMiddlewareError::DbWrapperError)
+    }
+
+    /// Returns up to `limit` non-zero storage slots of the account at the block, starting
+    /// at slot `start` if given. Follow `next_key` to read the next page.
+    pub async fn storage_range<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        address: T,
+        block: Option<BlockId>,
+        start: Option<H256>,
+        limit: usize,
+    ) -> Result<StorageRange, AkulaMiddlewareError<M>> {
+        let address = match address.into() {
+            NameOrAddress::Name(ens_name) => self
+                .inner
+                .resolve_name(&ens_name)
+                .await
+                .map_err(AkulaMiddlewareError::MiddlewareError)?,
+            NameOrAddress::Address(addr) => addr,
+        };
+        let block_id = utils::settled_block_id(block.map_or(
+            jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest),
+            |block_id| utils::ethers_block_id_to_akula(block_id),
+        ))?;
+
+        self.blocking(move |db| db.storage_range(address, block_id, start, limit))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| Ok(utils::storage_range_to_ethers(v)),
+            )
+    }
+
+    /// Streams every account that exists at the block in address order, with its storage
+    /// if `include_storage` is set, like `debug_dumpBlock`. The dump is read from a single
+    /// database snapshot and stops early once the stream is dropped.
+    pub fn dump_state(
+        &self,
+        block: Option<BlockId>,
+        include_storage: bool,
+    ) -> impl Stream<Item = Result<DumpAccount, AkulaMiddlewareError<M>>> + Send {
+        let block_id = utils::settled_block_id(block.map_or(
+            jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest),
+            |block_id| utils::ethers_block_id_to_akula(block_id),
+        ));
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(STATE_DUMP_BUFFER);
+        let error_sender = sender.clone();
+        let db_wrapper = self.db_wrapper.clone();
+        let refused = match block_id {
+            Ok(block_id) => {
+                if let Err(e) = self.pool.spawn(move || {
+                    let result = db_wrapper.dump_state(block_id, include_storage, |account| {
+                        sender.blocking_send(Ok(account)).is_ok()
+                    });
+                    if let Err(e) = result {
+                        let _ = sender.blocking_send(Err(e));
+                    }
+                }) {
+                    let _ = error_sender.try_send(Err(e));
+                }
+                None
+            }
+            Err(e) => Some(Err(e)),
+        };
+        drop(error_sender);
+
+        futures::stream::iter(refused).chain(futures::stream::unfold(
+            receiver,
+            |mut receiver| async move {
+                let account = receiver.recv().await?;
+                Some((
+                    account.map_or_else(
+                        |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                        |v| Ok(utils::dump_account_to_ethers(v)),
+                    ),
+                    receiver,
+                ))
+            },
+        ))
+    }
+
+    /// Writes the state dump of `dump_state` to `writer` as JSON lines, one account per
+    /// line, and returns the number of accounts written.
+    pub async fn write_state_dump_jsonl<W: AsyncWrite + Unpin + Send>(
+        &self,
+        block: Option<BlockId>,
+        include_storage: bool,
+        mut writer: W,
+    ) -> Result<u64, AkulaMiddlewareError<M>> {
+        let mut dump = Box::pin(self.dump_state(block, include_storage));
+        let mut count = 0;
+        while let Some(account) = dump.next().await {
+            let mut line = serde_json::to_vec(&account?).map_err(std::io::Error::from)?;
+            line.push(b'\n');
+            writer.write_all(&line).await?;
+            count += 1;
+        }
+        writer.flush().await?;
+
+        Ok(count)
+    }
+
+    /// Returns how every account and storage slot changed from the state at `from` to the
+    /// state at `to`, from the database's change sets rather than by re-executing blocks.
+    pub async fn state_diff(
+        &self,
+        from: BlockId,
+        to: BlockId,
+    ) -> Result<StateDiff, AkulaMiddlewareError<M>> {
+        let from = utils::settled_block_id(utils::ethers_block_id_to_akula(from))?;
+        let to = utils::settled_block_id(utils::ethers_block_id_to_akula(to))?;
+
+        self.blocking(move |db| db.state_diff(from, to))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| Ok(utils::account_changes_to_state_diff(v)),
+            )
+    }
+
+    /// Returns the blocks from `from` to `to` inclusive in which the account's balance, nonce
+    /// or code changed, from the database's account history index.
+    pub async fn account_history<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        address: T,
+        from: BlockId,
+        to: BlockId,
+    ) -> Result<Vec<U64>, AkulaMiddlewareError<M>> {
+        let address = match address.into() {
+            NameOrAddress::Name(ens_name) => self
+                .inner
+                .resolve_name(&ens_name)
+                .await
+                .map_err(AkulaMiddlewareError::MiddlewareError)?,
+            NameOrAddress::Address(addr) => addr,
+        };
+        let from = utils::settled_block_id(utils::ethers_block_id_to_akula(from))?;
+        let to = utils::settled_block_id(utils::ethers_block_id_to_akula(to))?;
+
+        self.blocking(move |db| db.account_history(address, from, to))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| {
+                    Ok(v.into_iter()
+                        .map(|block_number| block_number.0.into())
+                        .collect())
+                },
+            )
+    }
+
+    /// Returns the blocks from `from` to `to` inclusive in which the storage slot changed,
+    /// each with the slot's value after that block, from the database's storage history
+    /// index.
+    pub async fn storage_history<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        address: T,
+        location: H256,
+        from: BlockId,
+        to: BlockId,
+    ) -> Result<Vec<(U64, H256)>, AkulaMiddlewareError<M>> {
+        let address = match address.into() {
+            NameOrAddress::Name(ens_name) => self
+                .inner
+                .resolve_name(&ens_name)
+                .await
+                .map_err(AkulaMiddlewareError::MiddlewareError)?,
+            NameOrAddress::Address(addr) => addr,
+        };
+        let from = utils::settled_block_id(utils::ethers_block_id_to_akula(from))?;
+        let to = utils::settled_block_id(utils::ethers_block_id_to_akula(to))?;
+
+        self.blocking(move |db| db.storage_history(address, location, from, to))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| {
+                    Ok(v.into_iter()
+                        .map(|(block_number, value)| {
+                            (block_number.0.into(), H256(value.to_be_bytes()))
+                        })
+                        .collect())
+                },
+            )
+    }
+
+    /// Returns the receipts of every transaction in the block, executing it only once, or
+    /// `None` if the block is unknown.
+    pub async fn get_block_receipts_by_id<T: Into<BlockId> + Send + Sync>(
+        &self,
+        block: T,
+    ) -> Result<Option<Vec<TransactionReceipt>>, AkulaMiddlewareError<M>> {
+        let block_id = utils::settled_block_id(utils::ethers_block_id_to_akula(block.into()))?;
+
+        self.tracing(move |db, cancel| db.get_block_receipts(block_id, cancel))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| {
+                    Ok(v.map(|receipts| {
+                        receipts
+                            .iter()
+                            .map(utils::jsonrpc_receipt_to_ethers)
+                            .collect()
+                    }))
+                },
+            )
+    }
+
+    /// Sets the block window and percentile used by `get_gas_price` and
+    /// `estimate_eip1559_fees`.
+    pub fn with_gas_oracle(mut self, gas_oracle: GasOracleConfig) -> Self {
+        self.gas_oracle = gas_oracle;
+        self
+    }
+
+    /// Returns how often calls and transactions found the jump destination analysis of the
+    /// contract they call already cached. See `AnalysisCacheStats` for what is counted.
+    pub fn analysis_cache_stats(&self) -> AnalysisCacheStats {
+        self.db_wrapper.analysis_cache_stats()
+    }
+
+    /// Turns on sandbox mode: transactions sent with `send_sandbox_transaction` are
+    /// executed locally on top of `block` (the latest block if `None`), and their effects
+    /// are kept in memory. Queries against the latest or pending block see those effects.
+    ///
+    /// `send_transaction` still forwards to the inner middleware, as the transaction it
+    /// returns could only resolve through the inner provider.
+    pub fn with_sandbox(mut self, block: Option<u64>) -> Self {
+        self.sandbox = Some(Arc::new(Mutex::new(Sandbox::new(
+            block.map(akula::models::BlockNumber),
+        ))));
+        self
+    }
+
+    /// Executes the transaction in the sandbox and returns its receipt.
+    ///
+    /// The transaction is never signed, so it is recorded under
+    /// `keccak256(sender ++ nonce)`, the nonce being big-endian, and `get_transaction` and
+    /// `get_transaction_receipt` answer for that hash.
+    pub async fn send_sandbox_transaction(
+        &self,
+        tx: &TypedTransaction,
+    ) -> Result<TransactionReceipt, AkulaMiddlewareError<M>> {
+        let sandbox = self.sandbox.clone().ok_or_else(|| {
+            AkulaMiddlewareError::Unsupported("sandbox mode is not enabled".into())
+        })?;
+        let message_call = utils::ethers_typed_tx_to_message_call(tx)?;
+        let nonce = tx.nonce().map(|n| n.as_u64());
+
+        self.executing(move |db| {
+            let mut sandbox = sandbox
+                .lock()
+                .map_err(|_| anyhow::format_err!("sandbox lock poisoned"))?;
+            let mut sent = None;
+            db.sandbox_execute(&mut sandbox, [(message_call, nonce, ())], |(), result| {
+                sent = Some(result)
+            })?;
+            Ok(match sent.expect("the sandbox replies to every call")? {
+                Ok(hash) => Ok(sandbox
+                    .receipt(&hash)
+                    .cloned()
+                    .ok_or_else(|| anyhow::format_err!("sandbox receipt {hash:?} missing"))?),
+                Err(e) => Err(e),
+            })
+        })
+        .await?
+        .map(|receipt| utils::jsonrpc_receipt_to_ethers(&receipt))
+        .map_err(|e| AkulaMiddlewareError::ExecutionError(ExecutionError::Invalid(e.to_string())))
+    }
+
+    /// Sets how the pending block is built from the inner provider's txpool.
+    pub fn with_pending_block(mut self, pending_config: PendingBlockConfig) -> Self {
+        self.pending_config = pending_config;
+        self
+    }
+
+    /// Sets how many database reads and EVM executions may run at once on the middleware's
+    /// dedicated threads, and how long requests of each class may take before they fail.
+    pub fn with_blocking_pool(mut self, threads: usize, timeouts: RequestTimeouts) -> Self {
+        self.pool = BlockingPool::new(threads);
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Reads the inner provider's txpool, reusing the last read for the configured time.
+    /// Returns `None` if the txpool can't be read and the latest block is to be served as
+    /// the pending block instead.
+    async fn txpool(&self) -> Result<Option<Arc<TxpoolContent>>, AkulaMiddlewareError<M>> {
+        let cached = self
+            .txpool
+            .lock()
+            .map_err(|_| anyhow::format_err!("txpool lock poisoned"))?
+            .clone();
+        if let Some((read_at, txpool)) = cached {
+            if read_at.elapsed() < self.pending_config.txpool_ttl {
+                return Ok(txpool);
+            }
+        }
+
+        let txpool = match self.inner.txpool_content().await {
+            Ok(txpool) => Some(Arc::new(txpool)),
+            Err(_) if self.pending_config.latest_without_txpool => None,
+            Err(e) => return Err(AkulaMiddlewareError::MiddlewareError(e)),
+        };
+        *self
+            .txpool
+            .lock()
+            .map_err(|_| anyhow::format_err!("txpool lock poisoned"))? =
+            Some((Instant::now(), txpool.clone()));
+        Ok(txpool)
+    }
+
+    /// Executes the inner provider's pending transactions on top of the latest block in
+    /// the order of `utils::order_pending_transactions`. Transactions that don't fit in
+    /// the block's gas limit or can't be included after the sender's earlier ones are
+    /// skipped.
+    ///
+    /// The block is rebuilt whenever the latest block or the pending transactions change.
+    async fn pending_block(&self) -> Result<(u64, Arc<Sandbox>), AkulaMiddlewareError<M>> {
+        let head = self.blocking(|db| db.block_number()).await?.as_u64();
+        let ordered = self.txpool().await?.map_or_else(Vec::new, |txpool| {
+            utils::order_pending_transactions(txpool.pending.clone())
+        });
+        let transactions = ordered.iter().map(|(_, tx)| tx.hash).collect::<Vec<_>>();
+
+        if let Some(pending) = &*self
+            .pending
+            .lock()
+            .map_err(|_| anyhow::format_err!("pending block lock poisoned"))?
+        {
+            if pending.parent == head && pending.transactions == transactions {
+                return Ok((head, pending.sandbox.clone()));
+            }
+        }
+
+        let sandbox = self
+            .executing(move |db| {
+                let mut pending = Sandbox::pending_block(akula::models::BlockNumber(head));
+                let calls = ordered.into_iter().map(|(sender, tx)| {
+                    (
+                        utils::txpool_tx_to_message_call(sender, &tx),
+                        Some(tx.nonce.as_u64()),
+                        (),
+                    )
+                });
+                db.sandbox_execute(&mut pending, calls, |(), _| {})?;
+                Ok(Arc::new(pending))
+            })
+            .await?;
+
+        *self
+            .pending
+            .lock()
+            .map_err(|_| anyhow::format_err!("pending block lock poisoned"))? =
+            Some(PendingBlock {
+                parent: head,
+                transactions,
+                sandbox: sandbox.clone(),
+            });
+        Ok((head, sandbox))
+    }
+
+    /// Returns the nonce following the sender's transactions in the inner provider's
+    /// txpool that can be included after the latest block, without executing any.
+    async fn pending_nonce(&self, address: Address) -> Result<U256, AkulaMiddlewareError<M>> {
+        let latest = self
+            .blocking(move |db| {
+                db.get_transaction_count(
+                    address,
+                    jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest),
+                )
+            })
+            .await?
+            .as_u64();
+
+        let nonces = self
+            .txpool()
+            .await?
+            .and_then(|txpool| {
+                txpool
+                    .pending
+                    .get(&address)
+                    .map(|txs| txs.values().map(|tx| tx.nonce.as_u64()).collect())
+            })
+            .unwrap_or_else(BTreeSet::new);
+
+        let mut nonce = latest;
+        while nonces.contains(&nonce) {
+            nonce += 1;
         }
+        Ok(U256::from(nonce))
+    }
+
+    /// If the query should see local state on top of the database, i.e. the sandbox or the
+    /// pending block, returns the block that state sits on together with what `f` extracts
+    /// from it.
+    async fn state_view<T, F>(
+        &self,
+        block: Option<BlockId>,
+        f: F,
+    ) -> Result<Option<(jsonrpc::BlockId, T)>, AkulaMiddlewareError<M>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Sandbox) -> T + Send + 'static,
+    {
+        let block_id =
+            |base: u64| jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Number(base.into()));
+
+        match (&self.sandbox, block) {
+            (
+                Some(sandbox),
+                None | Some(BlockId::Number(BlockNumber::Latest | BlockNumber::Pending)),
+            ) => {
+                let sandbox = sandbox.clone();
+                Ok(self
+                    .blocking(move |db| {
+                        let mut sandbox = sandbox
+                            .lock()
+                            .map_err(|_| anyhow::format_err!("sandbox lock poisoned"))?;
+                        let base = db.sandbox_base(&mut sandbox)?;
+                        Ok(Some((block_id(base.0), f(&sandbox))))
+                    })
+                    .await?)
+            }
+            (None, Some(BlockId::Number(BlockNumber::Pending))) => {
+                let (base, pending) = self.pending_block().await?;
+                Ok(Some((block_id(base), f(&pending))))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Runs database reads on the blocking pool.
+    async fn blocking<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&DbWrapper<DB>) -> anyhow::Result<T> + Send + 'static,
+    {
+        self.blocking_for(self.timeouts.read, move |db, _| f(db))
+            .await
+    }
+
+    /// Runs EVM execution on top of a block on the blocking pool.
+    async fn executing<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&DbWrapper<DB>) -> anyhow::Result<T> + Send + 'static,
+    {
+        self.blocking_for(self.timeouts.execution, move |db, _| f(db))
+            .await
+    }
+
+    /// Runs block replays on the blocking pool, which give up once they are cancelled.
+    async fn tracing<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&DbWrapper<DB>, &Cancellation) -> anyhow::Result<T> + Send + 'static,
+    {
+        self.blocking_for(self.timeouts.trace, f).await
+    }
+
+    /// Runs `f` on the blocking pool, failing and cancelling it once `timeout` passes.
+    async fn blocking_for<T, F>(&self, timeout: Option<Duration>, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&DbWrapper<DB>, &Cancellation) -> anyhow::Result<T> + Send + 'static,
+    {
+        let db_wrapper = self.db_wrapper.clone();
+        self.pool
+            .run(timeout, move |cancel| f(&db_wrapper, cancel))
+            .await
     }
 }
 
@@ -67,41 +962,141 @@ where
     }
 
     async fn get_block_number(&self) -> Result<U64, Self::Error> {
-        self.db_wrapper
-            .block_number()
+        self.blocking(|db| db.block_number())
             .await
             .map_err(AkulaMiddlewareError::DbWrapperError)
     }
 
+    async fn get_chainid(&self) -> Result<U256, Self::Error> {
+        self.blocking(|db| db.chain_id()).await.map_or_else(
+            |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+            |v| Ok(U256::from(v.as_u64())),
+        )
+    }
+
+    async fn get_net_version(&self) -> Result<String, Self::Error> {
+        self.blocking(|db| db.net_version()).await.map_or_else(
+            |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+            |v| Ok(v.to_string()),
+        )
+    }
+
     async fn call(
         &self,
         tx: &TypedTransaction,
         block: Option<BlockId>,
     ) -> Result<Bytes, Self::Error> {
-        let block_id = block.map_or(
+        self.call_with_overrides(
+            tx,
+            block,
+            &StateOverride::default(),
+            &BlockOverride::default(),
+        )
+        .await
+    }
+
+    async fn create_access_list(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> Result<AccessListWithGasUsed, Self::Error> {
+        let block_id = utils::settled_block_id(block.map_or(
             jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest),
             |block_id| utils::ethers_block_id_to_akula(block_id),
-        );
+        ))?;
         let message_call = utils::ethers_typed_tx_to_message_call(tx)?;
 
-        self.db_wrapper
-            .call(message_call, block_id)
+        self.executing(move |db| db.create_access_list(message_call, block_id))
             .await
             .map_or_else(
                 |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
-                |v| Ok(Bytes::from(v.0)),
+                |(access_list, gas_used)| {
+                    Ok(AccessListWithGasUsed {
+                        access_list: utils::access_list_to_ethers(access_list),
+                        gas_used: U256::from(gas_used),
+                    })
+                },
             )
     }
 
     async fn estimate_gas(&self, tx: &TypedTransaction) -> Result<U256, Self::Error> {
-        let message_call = utils::ethers_typed_tx_to_message_call(tx)?;
+        self.estimate_gas_with_overrides(tx, None, &StateOverride::default())
+            .await
+    }
+
+    async fn fee_history<T: Into<U256> + serde::Serialize + Send + Sync>(
+        &self,
+        block_count: T,
+        last_block: BlockNumber,
+        reward_percentiles: &[f64],
+    ) -> Result<FeeHistory, Self::Error> {
+        let block_count = block_count.into().min(U256::from(u64::MAX)).as_u64();
+
+        let last_block =
+            utils::settled_block_number(utils::ethers_block_number_to_akula(last_block))?;
+        let reward_percentiles = reward_percentiles.to_vec();
+
+        self.tracing(move |db, cancel| {
+            db.fee_history(block_count, last_block, &reward_percentiles, cancel)
+        })
+        .await
+        .map_or_else(
+            |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+            |v| Ok(utils::fee_history_to_ethers(v)),
+        )
+    }
 
-        self.db_wrapper
-            .estimate_gas(message_call, jsonrpc::BlockNumber::Latest)
+    async fn get_gas_price(&self) -> Result<U256, Self::Error> {
+        let GasOracleConfig { blocks, percentile } = self.gas_oracle;
+
+        self.blocking(move |db| db.suggest_fees(blocks, percentile))
             .await
             .map_or_else(
                 |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
-                |v| Ok(U256::from(v.as_u64())),
+                |v| {
+                    Ok(utils::ethnum_u256_to_ethers(
+                        &(v.base_fee_per_gas + v.max_priority_fee_per_gas),
+                    ))
+                },
+            )
+    }
+
+    async fn estimate_eip1559_fees(
+        &self,
+        estimator: Option<fn(U256, Vec<Vec<U256>>) -> (U256, U256)>,
+    ) -> Result<(U256, U256), Self::Error> {
+        if let Some(estimator) = estimator {
+            let fee_history = self
+                .fee_history(
+                    ethers::utils::EIP1559_FEE_ESTIMATION_PAST_BLOCKS,
+                    BlockNumber::Latest,
+                    &[ethers::utils::EIP1559_FEE_ESTIMATION_REWARD_PERCENTILE],
+                )
+                .await?;
+            let base_fee_per_gas = fee_history
+                .base_fee_per_gas
+                .last()
+                .copied()
+                .unwrap_or_default();
+
+            return Ok(estimator(base_fee_per_gas, fee_history.reward));
+        }
+
+        let GasOracleConfig { blocks, percentile } = self.gas_oracle;
+
+        self.blocking(move |db| db.suggest_fees(blocks, percentile))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| {
+                    // Leave room for the base fee to double before the transaction is included.
+                    let max_fee_per_gas = v.base_fee_per_gas * akula::models::U256::from(2_u64)
+                        + v.max_priority_fee_per_gas;
+                    Ok((
+                        utils::ethnum_u256_to_ethers(&max_fee_per_gas),
+                        utils::ethnum_u256_to_ethers(&v.max_priority_fee_per_gas),
+                    ))
+                },
             )
     }
 
@@ -122,8 +1117,21 @@ where
             |block_id| utils::ethers_block_id_to_akula(block_id),
         );
 
-        self.db_wrapper
-            .get_balance(from, block_id)
+        let block_id = match self
+            .state_view(block, move |sandbox| {
+                sandbox
+                    .state()
+                    .get(&from)
+                    .and_then(|account| account.balance)
+            })
+            .await?
+        {
+            Some((_, Some(balance))) => return Ok(utils::ethnum_u256_to_ethers(&balance)),
+            Some((base, None)) => base,
+            None => block_id,
+        };
+
+        self.blocking(move |db| db.get_balance(from, block_id))
             .await
             .map_or_else(
                 |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
@@ -138,9 +1146,9 @@ where
     where
         T: Into<BlockId> + Send + Sync,
     {
-        let block_id = utils::ethers_block_id_to_akula(block_hash_or_number.into());
-        self.db_wrapper
-            .get_block(block_id, false)
+        let block_id =
+            utils::settled_block_id(utils::ethers_block_id_to_akula(block_hash_or_number.into()))?;
+        self.blocking(move |db| db.get_block(block_id, false))
             .await
             .map_or_else(
                 |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
@@ -155,20 +1163,32 @@ where
     where
         T: Into<BlockId> + Send + Sync,
     {
-        let block_id = utils::ethers_block_id_to_akula(block_hash_or_number.into());
+        let block_id =
+            utils::settled_block_id(utils::ethers_block_id_to_akula(block_hash_or_number.into()))?;
 
-        self.db_wrapper.get_block(block_id, true).await.map_or_else(
-            |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
-            |v| Ok(v.map(|block| utils::jsonrpc_block_with_txs_to_ethers(block))),
-        )
+        self.blocking(move |db| db.get_block(block_id, true))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| Ok(v.map(|block| utils::jsonrpc_block_with_txs_to_ethers(block))),
+            )
     }
 
     async fn get_transaction<T: Into<TxHash> + Send + Sync>(
         &self,
         transaction_hash: T,
     ) -> Result<Option<Transaction>, Self::Error> {
-        self.db_wrapper
-            .get_transaction_by_hash(transaction_hash.into())
+        let transaction_hash = transaction_hash.into();
+        if let Some((_, Some(tx))) = self
+            .state_view(None, move |sandbox| {
+                sandbox.transaction(&transaction_hash).cloned()
+            })
+            .await?
+        {
+            return Ok(Some(utils::jsonrpc_tx_to_ethers(&tx)));
+        }
+
+        self.blocking(move |db| db.get_transaction_by_hash(transaction_hash))
             .await
             .map_or_else(
                 |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
@@ -192,13 +1212,26 @@ where
                 .map_err(AkulaMiddlewareError::MiddlewareError)?,
             NameOrAddress::Address(addr) => addr,
         };
-        let block_id = block_id.map_or(
+        let block = block_id;
+        if self.sandbox.is_none() && matches!(block, Some(BlockId::Number(BlockNumber::Pending))) {
+            return self.pending_nonce(from).await;
+        }
+        let block_id = block.map_or(
             jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest),
             |block_id| utils::ethers_block_id_to_akula(block_id),
         );
+        let block_id = match self
+            .state_view(block, move |sandbox| {
+                sandbox.state().get(&from).and_then(|account| account.nonce)
+            })
+            .await?
+        {
+            Some((_, Some(nonce))) => return Ok(U256::from(nonce)),
+            Some((base, None)) => base,
+            None => block_id,
+        };
 
-        self.db_wrapper
-            .get_transaction_count(from, block_id)
+        self.blocking(move |db| db.get_transaction_count(from, block_id))
             .await
             .map_or_else(
                 |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
@@ -228,12 +1261,23 @@ where
             |block_id| utils::ethers_block_id_to_akula(block_id),
         );
 
-        self.db_wrapper
-            .get_storage_at(
-                at,
-                akula::models::U256::from_be_bytes(*location.as_fixed_bytes()),
-                block_id,
-            )
+        let block_id = match self
+            .state_view(block, move |sandbox| {
+                sandbox
+                    .state()
+                    .get(&at)
+                    .and_then(|account| account.storage(&location))
+            })
+            .await?
+        {
+            Some((_, Some(value))) => return Ok(H256(value.to_be_bytes())),
+            Some((base, None)) => base,
+            None => block_id,
+        };
+
+        let location = akula::models::U256::from_be_bytes(*location.as_fixed_bytes());
+
+        self.blocking(move |db| db.get_storage_at(at, location, block_id))
             .await
             .map_or_else(
                 |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
@@ -258,18 +1302,73 @@ where
             |block_id| utils::ethers_block_id_to_akula(block_id),
         );
 
-        self.db_wrapper.get_code(at, block_id).await.map_or_else(
-            |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
-            |v| Ok(Bytes::from(v.0)),
-        )
+        let block_id = match self
+            .state_view(block, move |sandbox| {
+                sandbox
+                    .state()
+                    .get(&at)
+                    .and_then(|account| account.code.clone())
+            })
+            .await?
+        {
+            Some((_, Some(code))) => return Ok(Bytes::from(code.to_vec())),
+            Some((base, None)) => base,
+            None => block_id,
+        };
+
+        self.blocking(move |db| db.get_code(at, block_id))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| Ok(Bytes::from(v.0)),
+            )
+    }
+
+    async fn get_proof<T>(
+        &self,
+        from: T,
+        locations: Vec<H256>,
+        block: Option<BlockId>,
+    ) -> Result<EIP1186ProofResponse, Self::Error>
+    where
+        T: Into<NameOrAddress> + Send + Sync,
+    {
+        let from = match from.into() {
+            NameOrAddress::Name(ens_name) => self
+                .inner
+                .resolve_name(&ens_name)
+                .await
+                .map_err(AkulaMiddlewareError::MiddlewareError)?,
+            NameOrAddress::Address(addr) => addr,
+        };
+        let block_id = utils::settled_block_id(block.map_or(
+            jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest),
+            |block_id| utils::ethers_block_id_to_akula(block_id),
+        ))?;
+
+        self.blocking(move |db| db.get_proof(from, locations, block_id))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| Ok(utils::account_proof_to_ethers(v)),
+            )
     }
 
     async fn get_transaction_receipt<T: Into<TxHash> + Sync + Send>(
         &self,
         transaction_hash: T,
     ) -> Result<Option<TransactionReceipt>, Self::Error> {
-        self.db_wrapper
-            .get_transaction_receipt(transaction_hash.into())
+        let transaction_hash = transaction_hash.into();
+        if let Some((_, Some(receipt))) = self
+            .state_view(None, move |sandbox| {
+                sandbox.receipt(&transaction_hash).cloned()
+            })
+            .await?
+        {
+            return Ok(Some(utils::jsonrpc_receipt_to_ethers(&receipt)));
+        }
+
+        self.tracing(move |db, cancel| db.get_transaction_receipt(transaction_hash, cancel))
             .await
             .map_or_else(
                 |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
@@ -277,12 +1376,136 @@ where
             )
     }
 
+    async fn get_block_receipts<T: Into<BlockNumber> + Send + Sync>(
+        &self,
+        block: T,
+    ) -> Result<Vec<TransactionReceipt>, Self::Error> {
+        let block = block.into();
+        self.get_block_receipts_by_id(BlockId::Number(block))
+            .await?
+            .ok_or_else(|| {
+                AkulaMiddlewareError::DbWrapperError(anyhow::format_err!(
+                    "block {block:?} not found"
+                ))
+            })
+    }
+
+    async fn get_logs(&self, filter: &Filter) -> Result<Vec<Log>, Self::Error> {
+        let filter = utils::ethers_filter_to_log_filter(filter);
+        if let LogFilterBlocks::Range { from, to } = &filter.blocks {
+            utils::settled_block_number(*from)?;
+            utils::settled_block_number(*to)?;
+        }
+
+        self.tracing(move |db, cancel| db.get_logs(filter, cancel))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| Ok(v.iter().map(utils::jsonrpc_log_to_ethers).collect()),
+            )
+    }
+
+    async fn trace_call<T: Into<TypedTransaction> + Send + Sync>(
+        &self,
+        req: T,
+        trace_type: Vec<TraceType>,
+        block: Option<BlockNumber>,
+    ) -> Result<BlockTrace, Self::Error> {
+        if let Some(trace_type) = trace_type
+            .iter()
+            .find(|trace_type| !matches!(trace_type, TraceType::Trace))
+        {
+            return Err(AkulaMiddlewareError::Unsupported(format!(
+                "trace type {trace_type:?}"
+            )));
+        }
+        let block_id = block.map_or(
+            jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest),
+            |block| jsonrpc::BlockId::Number(utils::ethers_block_number_to_akula(block)),
+        );
+        let block_id = utils::settled_block_id(block_id)?;
+        let message_call = utils::ethers_typed_tx_to_message_call(&req.into())?;
+
+        self.executing(move |db| db.trace_call(message_call, block_id))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |(output, trace)| {
+                    Ok(BlockTrace {
+                        output: Bytes::from(output),
+                        trace: trace_type.contains(&TraceType::Trace).then_some(trace),
+                        vm_trace: None,
+                        state_diff: None,
+                        transaction_hash: None,
+                    })
+                },
+            )
+    }
+
+    async fn trace_transaction(&self, hash: H256) -> Result<Vec<Trace>, Self::Error> {
+        self.tracing(move |db, cancel| db.trace_transaction(hash, cancel))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| Ok(v.unwrap_or_default()),
+            )
+    }
+
+    async fn trace_block(&self, block: BlockNumber) -> Result<Vec<Trace>, Self::Error> {
+        let block_id = jsonrpc::BlockId::Number(utils::settled_block_number(
+            utils::ethers_block_number_to_akula(block),
+        )?);
+
+        self.tracing(move |db, cancel| db.trace_block(block_id, cancel))
+            .await
+            .map_or_else(
+                |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+                |v| Ok(v.unwrap_or_default()),
+            )
+    }
+
+    async fn trace_replay_block_transactions(
+        &self,
+        block: BlockNumber,
+        trace_type: Vec<TraceType>,
+    ) -> Result<Vec<BlockTrace>, Self::Error> {
+        let block_id = jsonrpc::BlockId::Number(utils::settled_block_number(
+            utils::ethers_block_number_to_akula(block),
+        )?);
+
+        let trace_types = utils::ethers_trace_types_to_akula(&trace_type);
+
+        self.tracing(move |db, cancel| {
+            db.trace_replay_block_transactions(block_id, trace_types, cancel)
+        })
+        .await
+        .map_or_else(
+            |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
+            |v| Ok(v.unwrap_or_default()),
+        )
+    }
+
+    async fn debug_trace_transaction(
+        &self,
+        tx_hash: TxHash,
+        trace_options: GethDebugTracingOptions,
+    ) -> Result<GethTrace, ProviderError> {
+        let config = utils::ethers_tracing_options_to_struct_logger_config(&trace_options);
+
+        self.tracing(move |db, cancel| db.debug_trace_transaction(tx_hash, config, cancel))
+            .await
+            .and_then(|v| v.ok_or_else(|| anyhow::format_err!("transaction {tx_hash} not found")))
+            .map_err(|e| ProviderError::CustomError(e.to_string()))
+    }
+
     async fn get_uncle_count<T: Into<BlockId> + Send + Sync>(
         &self,
         block_hash_or_number: T,
     ) -> Result<U256, Self::Error> {
-        self.db_wrapper
-            .get_uncle_count(utils::ethers_block_id_to_akula(block_hash_or_number.into()))
+        let block_id =
+            utils::settled_block_id(utils::ethers_block_id_to_akula(block_hash_or_number.into()))?;
+
+        self.blocking(move |db| db.get_uncle_count(block_id))
             .await
             .map_or_else(
                 |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
@@ -295,11 +1518,10 @@ where
         block_hash_or_number: T,
         idx: U64,
     ) -> Result<Option<Block<H256>>, Self::Error> {
-        self.db_wrapper
-            .get_uncle_by_block_number_and_index(
-                utils::ethers_block_id_to_akula(block_hash_or_number.into()),
-                idx,
-            )
+        let block_id =
+            utils::settled_block_id(utils::ethers_block_id_to_akula(block_hash_or_number.into()))?;
+
+        self.blocking(move |db| db.get_uncle_by_block_number_and_index(block_id, idx))
             .await
             .map_or_else(
                 |e| Err(AkulaMiddlewareError::DbWrapperError(e)),
@@ -0,0 +1,214 @@
+use anyhow::format_err;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Set once the request a job runs for has timed out or was dropped. Jobs that loop over
+/// many transactions check it between them and give up, so that they don't hold a thread
+/// for a result nobody waits for anymore.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fails if the request was cancelled.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.is_cancelled() {
+            return Err(format_err!("request cancelled"));
+        }
+        Ok(())
+    }
+
+    fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Cancels the job of a request once the request is done with it, however it ends.
+struct CancelOnDrop(Cancellation);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Dedicated threads that run blocking database reads and EVM execution, so that they
+/// don't stall the async executor. At most `threads` jobs run at once; the rest queue up.
+#[derive(Debug)]
+pub struct BlockingPool {
+    sender: Mutex<mpsc::Sender<Job>>,
+    threads: usize,
+}
+
+impl BlockingPool {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = threads.max(1);
+        for i in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("akula-middleware-{i}"))
+                .spawn(move || loop {
+                    // Workers exit once the pool, and with it the sender, is dropped.
+                    let job = match receiver.lock().map(|receiver| receiver.recv()) {
+                        Ok(Ok(job)) => job,
+                        _ => break,
+                    };
+                    // A panicking job drops its result sender, which `run` reports.
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                })
+                .expect("failed to spawn blocking pool thread");
+        }
+
+        Self {
+            sender: Mutex::new(sender),
+            threads,
+        }
+    }
+
+    /// Returns how many jobs may run at once.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Queues `f` to run on one of the pool's threads without waiting for it to finish,
+    /// and without a timeout.
+    pub fn spawn(&self, f: impl FnOnce() + Send + 'static) -> anyhow::Result<()> {
+        self.sender
+            .lock()
+            .map_err(|_| format_err!("blocking pool lock poisoned"))?
+            .send(Box::new(f))
+            .map_err(|_| format_err!("blocking pool is shut down"))
+    }
+
+    /// Runs `f` on one of the pool's threads. If `f` hasn't finished within `timeout`,
+    /// queueing included, an error is returned; `f` is skipped if it hasn't started yet,
+    /// and is told through its `Cancellation` otherwise. The same happens if the returned
+    /// future is dropped.
+    pub async fn run<T, F>(&self, timeout: Option<Duration>, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Cancellation) -> anyhow::Result<T> + Send + 'static,
+    {
+        let cancellation = Cancellation::default();
+        let _cancel_on_drop = CancelOnDrop(cancellation.clone());
+
+        let (result_sender, result_receiver) = oneshot::channel();
+        self.sender
+            .lock()
+            .map_err(|_| format_err!("blocking pool lock poisoned"))?
+            .send(Box::new(move || {
+                if !cancellation.is_cancelled() {
+                    let _ = result_sender.send(f(&cancellation));
+                }
+            }))
+            .map_err(|_| format_err!("blocking pool is shut down"))?;
+
+        let result = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, result_receiver)
+                .await
+                .map_err(|_| format_err!("request timed out after {timeout:?}"))?,
+            None => result_receiver.await,
+        };
+        result.map_err(|_| format_err!("blocking pool thread panicked"))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[tokio::test]
+    async fn jobs_queue_behind_busy_threads() {
+        let pool = BlockingPool::new(1);
+        let (release, released) = mpsc::channel::<()>();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let first = pool.run(None, {
+            let order = order.clone();
+            move |_: &Cancellation| {
+                released.recv().unwrap();
+                order.lock().unwrap().push(1);
+                Ok(1)
+            }
+        });
+        let second = pool.run(None, {
+            let order = order.clone();
+            move |_: &Cancellation| {
+                order.lock().unwrap().push(2);
+                Ok(2)
+            }
+        });
+        release.send(()).unwrap();
+
+        let (first, second) = tokio::join!(first, second);
+        assert_eq!((first.unwrap(), second.unwrap()), (1, 2));
+        assert_eq!(*order.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn timed_out_jobs_are_cancelled_or_skipped() {
+        let pool = BlockingPool::new(1);
+        let iterations = Arc::new(AtomicUsize::new(0));
+        let ran = Arc::new(AtomicBool::new(false));
+
+        let looping = pool.run(Some(Duration::from_millis(50)), {
+            let iterations = iterations.clone();
+            move |cancellation: &Cancellation| loop {
+                cancellation.check()?;
+                iterations.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        let queued = pool.run(Some(Duration::from_millis(10)), {
+            let ran = ran.clone();
+            move |_: &Cancellation| {
+                ran.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+        });
+        let (looping, queued): (anyhow::Result<()>, anyhow::Result<()>) =
+            tokio::join!(looping, queued);
+        assert!(looping.unwrap_err().to_string().contains("timed out"));
+        assert!(queued.unwrap_err().to_string().contains("timed out"));
+
+        // The loop noticed its cancellation and freed the only thread, and the job queued
+        // behind it was skipped.
+        assert_eq!(pool.run(None, |_: &Cancellation| Ok(7)).await.unwrap(), 7);
+        assert!(!ran.load(Ordering::Relaxed));
+        let stopped_at = iterations.load(Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(iterations.load(Ordering::Relaxed), stopped_at);
+    }
+
+    #[tokio::test]
+    async fn panics_fail_only_their_request() {
+        let pool = BlockingPool::new(1);
+
+        let panicked = pool
+            .run(None, |_: &Cancellation| -> anyhow::Result<()> {
+                panic!("boom")
+            })
+            .await;
+        assert_eq!(
+            panicked.unwrap_err().to_string(),
+            "blocking pool thread panicked"
+        );
+        assert_eq!(pool.run(None, |_: &Cancellation| Ok(1)).await.unwrap(), 1);
+    }
+}
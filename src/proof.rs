@@ -0,0 +1,866 @@
+use akula::{
+    crypto::keccak256,
+    models::{Account, H256, U256},
+};
+use anyhow::format_err;
+use ethers::utils::rlp::RlpStream;
+use std::iter::Peekable;
+
+/// Root of a trie and the proofs of its target keys, each ordered from the root down.
+#[derive(Clone, Debug)]
+pub struct TrieProof {
+    pub root: H256,
+    pub proofs: Vec<Vec<Vec<u8>>>,
+}
+
+enum NodeRef {
+    Hash(H256),
+    Inline(Vec<u8>),
+}
+
+enum Child {
+    Hash(H256),
+    Subtrie(Subtrie),
+}
+
+enum Subtrie {
+    Leaf {
+        path: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Branch {
+        path: Vec<u8>,
+        children: [Option<NodeRef>; 16],
+    },
+}
+
+impl Subtrie {
+    fn path_mut(&mut self) -> &mut Vec<u8> {
+        match self {
+            Subtrie::Leaf { path, .. } => path,
+            Subtrie::Branch { path, .. } => path,
+        }
+    }
+}
+
+/// Branch node of the head trie as kept in the intermediate hashes tables. Bit `i` of
+/// `state_mask` is set if the branch has a child at nibble `i`, and bit `i` of `hash_mask`
+/// if that child's hash is stored in `hashes`, which are ordered by nibble.
+#[derive(Clone, Debug)]
+pub struct StoredBranch {
+    pub state_mask: u16,
+    pub hash_mask: u16,
+    pub hashes: Vec<H256>,
+}
+
+impl StoredBranch {
+    fn child_hash(&self, nibble: u8) -> Option<H256> {
+        let bit = 1 << nibble;
+        if self.hash_mask & bit == 0 {
+            return None;
+        }
+        self.hashes
+            .get((self.hash_mask & (bit - 1)).count_ones() as usize)
+            .copied()
+    }
+}
+
+/// A trie to prove, given as the stored branch nodes of the head trie and the leaves of
+/// the trie being proven, which may differ from the head's under changed prefixes.
+/// Prefixes are paths of nibbles from the root.
+pub trait TrieSource {
+    /// Stored branch node of the head trie at `prefix`.
+    fn branch(&mut self, prefix: &[u8]) -> anyhow::Result<Option<StoredBranch>>;
+
+    /// Path of the first stored branch node of the head trie strictly below `prefix`.
+    fn first_branch_below(&mut self, prefix: &[u8]) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Whether any leaf under `prefix` differs from the head trie.
+    fn changed(&self, prefix: &[u8]) -> bool;
+
+    /// Leaves under `prefix` in ascending key order. Only asked for subtries without
+    /// stored branch nodes, which hold few leaves.
+    fn leaves(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<(H256, Vec<u8>)>>;
+}
+
+/// Builds a Merkle Patricia trie from leaves sorted by key, keeping only the nodes
+/// that lie on the paths to the target keys. Everything else is hashed away as soon
+/// as its subtrie is complete, so memory use does not depend on the number of leaves.
+pub struct ProofBuilder {
+    targets: Vec<[u8; 64]>,
+    proofs: Vec<Vec<(usize, Vec<u8>)>>,
+}
+
+impl ProofBuilder {
+    pub fn new(targets: &[H256]) -> Self {
+        Self {
+            targets: targets.iter().map(to_nibbles).collect(),
+            proofs: vec![vec![]; targets.len()],
+        }
+    }
+
+    /// Consumes `(key, value)` leaves in ascending key order, values being the RLP
+    /// encoding stored in the leaf node.
+    pub fn build<I>(mut self, leaves: I) -> anyhow::Result<TrieProof>
+    where
+        I: Iterator<Item = anyhow::Result<(H256, Vec<u8>)>>,
+    {
+        let mut leaves = leaves.peekable();
+
+        let subtrie = if next_key(&mut leaves)?.is_some() {
+            Some(self.subtrie(&mut vec![], &mut leaves)?)
+        } else {
+            None
+        };
+        Ok(self.finish(subtrie))
+    }
+
+    /// Builds the trie of `source`, taking the hashes of subtries that neither changed
+    /// nor lie on a target path from the stored branch nodes instead of their leaves.
+    pub fn build_from<S: TrieSource>(mut self, source: &mut S) -> anyhow::Result<TrieProof> {
+        let subtrie = self.node(source, &mut vec![])?;
+        Ok(self.finish(subtrie))
+    }
+
+    fn finish(mut self, subtrie: Option<Subtrie>) -> TrieProof {
+        let root = if let Some(subtrie) = subtrie {
+            let node = self.encode(&[], subtrie);
+            // The root node is part of every proof, even when it is small enough to be inlined.
+            if node.len() < 32 {
+                for proof in &mut self.proofs {
+                    proof.push((0, node.clone()));
+                }
+            }
+            keccak256(&node)
+        } else {
+            empty_root()
+        };
+
+        TrieProof {
+            root,
+            proofs: self
+                .proofs
+                .into_iter()
+                .map(|mut proof| {
+                    proof.sort_by_key(|(depth, _)| *depth);
+                    proof.into_iter().map(|(_, node)| node).collect()
+                })
+                .collect(),
+        }
+    }
+
+    fn node<S: TrieSource>(
+        &mut self,
+        source: &mut S,
+        prefix: &mut Vec<u8>,
+    ) -> anyhow::Result<Option<Subtrie>> {
+        let branch = source.branch(prefix)?;
+        let head_children = match &branch {
+            Some(branch) => branch.state_mask,
+            // Without a stored branch here, the head subtrie is either an extension to the
+            // first stored branch below, or holds no branch with branch children.
+            None => match source.first_branch_below(prefix)? {
+                Some(path) => 1 << path[prefix.len()],
+                None => {
+                    let mut leaves = source.leaves(prefix)?.into_iter().map(Ok).peekable();
+                    return if leaves.peek().is_some() {
+                        self.subtrie(prefix, &mut leaves).map(Some)
+                    } else {
+                        Ok(None)
+                    };
+                }
+            },
+        };
+
+        let mut children = vec![];
+        for nibble in 0..16 {
+            prefix.push(nibble);
+            let changed = source.changed(prefix);
+            if head_children & (1 << nibble) != 0 || changed {
+                let hash = branch
+                    .as_ref()
+                    .filter(|_| !changed && !self.on_target(prefix))
+                    .and_then(|branch| branch.child_hash(nibble));
+                if let Some(hash) = hash {
+                    children.push((nibble, Child::Hash(hash)));
+                } else if let Some(subtrie) = self.node(source, prefix)? {
+                    children.push((nibble, Child::Subtrie(subtrie)));
+                }
+            }
+            prefix.pop();
+        }
+
+        if children.len() > 1 {
+            let mut refs: [Option<NodeRef>; 16] = Default::default();
+            for (nibble, child) in children {
+                refs[usize::from(nibble)] = Some(match child {
+                    Child::Hash(hash) => NodeRef::Hash(hash),
+                    Child::Subtrie(subtrie) => {
+                        prefix.push(nibble);
+                        let node_ref = self.node_ref(prefix, subtrie);
+                        prefix.pop();
+                        node_ref
+                    }
+                });
+            }
+            return Ok(Some(Subtrie::Branch {
+                path: vec![],
+                children: refs,
+            }));
+        }
+
+        // A branch left with a single child is merged into it, which takes the child's
+        // structure even if its hash is stored.
+        let (nibble, child) = match children.pop() {
+            Some(child) => child,
+            None => return Ok(None),
+        };
+        let mut child = match child {
+            Child::Subtrie(subtrie) => subtrie,
+            Child::Hash(_) => {
+                prefix.push(nibble);
+                let subtrie = self.node(source, prefix)?;
+                prefix.pop();
+                subtrie.ok_or_else(|| format_err!("stored subtrie at {prefix:?} has no leaves"))?
+            }
+        };
+        child.path_mut().insert(0, nibble);
+        Ok(Some(child))
+    }
+
+    fn subtrie<I>(
+        &mut self,
+        prefix: &mut Vec<u8>,
+        leaves: &mut Peekable<I>,
+    ) -> anyhow::Result<Subtrie>
+    where
+        I: Iterator<Item = anyhow::Result<(H256, Vec<u8>)>>,
+    {
+        if prefix.len() == 64 {
+            let (_, value) = leaves
+                .next()
+                .expect("subtries are only built for existing leaves")?;
+            return Ok(Subtrie::Leaf {
+                path: vec![],
+                value,
+            });
+        }
+
+        let mut children = vec![];
+        while let Some(key) = next_key(leaves)? {
+            let nibbles = to_nibbles(&key);
+            if !nibbles.starts_with(prefix) {
+                break;
+            }
+
+            let nibble = nibbles[prefix.len()];
+            prefix.push(nibble);
+            let child = self.subtrie(prefix, leaves)?;
+            prefix.pop();
+            children.push((nibble, child));
+        }
+
+        if children.len() == 1 {
+            let (nibble, mut child) = children.pop().unwrap();
+            child.path_mut().insert(0, nibble);
+            return Ok(child);
+        }
+
+        let mut refs: [Option<NodeRef>; 16] = Default::default();
+        for (nibble, child) in children {
+            prefix.push(nibble);
+            refs[usize::from(nibble)] = Some(self.node_ref(prefix, child));
+            prefix.pop();
+        }
+
+        Ok(Subtrie::Branch {
+            path: vec![],
+            children: refs,
+        })
+    }
+
+    /// Encodes the subtrie starting at `start` into the reference its parent holds.
+    fn node_ref(&mut self, start: &[u8], subtrie: Subtrie) -> NodeRef {
+        let node = self.encode(start, subtrie);
+        if node.len() < 32 {
+            NodeRef::Inline(node)
+        } else {
+            NodeRef::Hash(keccak256(&node))
+        }
+    }
+
+    /// Encodes the subtrie starting at `start` into a node, recording it and any
+    /// node it wraps if they are on a target path.
+    fn encode(&mut self, start: &[u8], subtrie: Subtrie) -> Vec<u8> {
+        match subtrie {
+            Subtrie::Leaf { path, value } => {
+                let mut stream = RlpStream::new_list(2);
+                stream.append(&hex_prefix(&path, true));
+                stream.append(&value);
+                let node = stream.out().to_vec();
+                self.record(start, &node);
+                node
+            }
+            Subtrie::Branch { path, children } => {
+                let mut stream = RlpStream::new_list(17);
+                for child in &children {
+                    match child {
+                        Some(NodeRef::Hash(hash)) => {
+                            stream.append(&hash.as_bytes().to_vec());
+                        }
+                        Some(NodeRef::Inline(node)) => {
+                            stream.append_raw(node, 1);
+                        }
+                        None => {
+                            stream.append_empty_data();
+                        }
+                    }
+                }
+                stream.append_empty_data();
+                let branch = stream.out().to_vec();
+
+                if path.is_empty() {
+                    self.record(start, &branch);
+                    return branch;
+                }
+
+                self.record(&[start, &path].concat(), &branch);
+                let mut stream = RlpStream::new_list(2);
+                stream.append(&hex_prefix(&path, false));
+                if branch.len() < 32 {
+                    stream.append_raw(&branch, 1);
+                } else {
+                    stream.append(&keccak256(&branch).as_bytes().to_vec());
+                }
+                let extension = stream.out().to_vec();
+                self.record(start, &extension);
+                extension
+            }
+        }
+    }
+
+    fn on_target(&self, prefix: &[u8]) -> bool {
+        self.targets.iter().any(|target| target.starts_with(prefix))
+    }
+
+    fn record(&mut self, prefix: &[u8], node: &[u8]) {
+        // Nodes shorter than a hash are inlined into their parent and are not proof elements.
+        if node.len() < 32 {
+            return;
+        }
+
+        for (target, proof) in self.targets.iter().zip(&mut self.proofs) {
+            if target.starts_with(prefix) {
+                proof.push((prefix.len(), node.to_vec()));
+            }
+        }
+    }
+}
+
+/// Merges sorted base leaves with sorted overriding entries, where `None` deletes the leaf.
+pub struct Overlaid<I, O, V>
+where
+    I: Iterator<Item = anyhow::Result<(H256, V)>>,
+    O: Iterator<Item = anyhow::Result<(H256, Option<V>)>>,
+{
+    base: Peekable<I>,
+    overlay: Peekable<O>,
+}
+
+impl<I, O, V> Overlaid<I, O, V>
+where
+    I: Iterator<Item = anyhow::Result<(H256, V)>>,
+    O: Iterator<Item = anyhow::Result<(H256, Option<V>)>>,
+{
+    pub fn new(base: I, overlay: O) -> Self {
+        Self {
+            base: base.peekable(),
+            overlay: overlay.peekable(),
+        }
+    }
+}
+
+impl<I, O, V> Iterator for Overlaid<I, O, V>
+where
+    I: Iterator<Item = anyhow::Result<(H256, V)>>,
+    O: Iterator<Item = anyhow::Result<(H256, Option<V>)>>,
+{
+    type Item = anyhow::Result<(H256, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let base_key = match self.base.peek() {
+                Some(Ok((key, _))) => Some(*key),
+                Some(Err(_)) => return self.base.next(),
+                None => None,
+            };
+            let overlay_key = match self.overlay.peek() {
+                Some(Ok((key, _))) => Some(*key),
+                Some(Err(_)) => return self.overlay.next().and_then(Result::err).map(Err),
+                None => None,
+            };
+
+            match (base_key, overlay_key) {
+                (Some(base_key), Some(overlay_key)) if base_key < overlay_key => {
+                    return self.base.next();
+                }
+                (Some(base_key), Some(overlay_key)) => {
+                    if base_key == overlay_key {
+                        self.base.next();
+                    }
+                    if let Some(Ok((key, Some(value)))) = self.overlay.next() {
+                        return Some(Ok((key, value)));
+                    }
+                }
+                (Some(_), None) => return self.base.next(),
+                (None, Some(_)) => {
+                    if let Some(Ok((key, Some(value)))) = self.overlay.next() {
+                        return Some(Ok((key, value)));
+                    }
+                }
+                (None, None) => return None,
+            }
+        }
+    }
+}
+
+const EMPTY_STRING_CODE: u8 = 0x80;
+
+pub fn empty_root() -> H256 {
+    keccak256(&[EMPTY_STRING_CODE])
+}
+
+/// RLP encoding of an account as stored in the state trie.
+pub fn account_leaf(account: &Account, storage_root: H256) -> Vec<u8> {
+    let mut stream = RlpStream::new_list(4);
+    stream.append(&account.nonce);
+    stream.append(&trimmed_be_bytes(account.balance));
+    stream.append(&storage_root.as_bytes().to_vec());
+    stream.append(&account.code_hash.as_bytes().to_vec());
+    stream.out().to_vec()
+}
+
+/// RLP encoding of a storage value as stored in a storage trie.
+pub fn storage_leaf(value: U256) -> Vec<u8> {
+    let mut stream = RlpStream::new();
+    stream.append(&trimmed_be_bytes(value));
+    stream.out().to_vec()
+}
+
+fn trimmed_be_bytes(value: U256) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    bytes[value.leading_zeros() as usize / 8..].to_vec()
+}
+
+fn next_key<I>(leaves: &mut Peekable<I>) -> anyhow::Result<Option<H256>>
+where
+    I: Iterator<Item = anyhow::Result<(H256, Vec<u8>)>>,
+{
+    match leaves.peek() {
+        Some(Ok((key, _))) => Ok(Some(*key)),
+        Some(Err(_)) => Err(leaves.next().unwrap().unwrap_err()),
+        None => Ok(None),
+    }
+}
+
+/// Lowest and highest keys whose nibbles start with `prefix`.
+pub fn prefix_bounds(prefix: &[u8]) -> (H256, H256) {
+    let (mut low, mut high) = (H256::zero(), H256::repeat_byte(0xff));
+    for (i, nibble) in prefix.iter().enumerate() {
+        let shift = if i % 2 == 0 { 4 } else { 0 };
+        low.0[i / 2] |= nibble << shift;
+        high.0[i / 2] &= !(0x0f << shift) | (nibble << shift);
+    }
+    (low, high)
+}
+
+fn to_nibbles(key: &H256) -> [u8; 64] {
+    let mut nibbles = [0; 64];
+    for (i, byte) in key.as_bytes().iter().enumerate() {
+        nibbles[2 * i] = byte >> 4;
+        nibbles[2 * i + 1] = byte & 0x0f;
+    }
+    nibbles
+}
+
+fn hex_prefix(path: &[u8], leaf: bool) -> Vec<u8> {
+    let flag = if leaf { 0x20 } else { 0x00 };
+    let mut encoded = Vec::with_capacity(path.len() / 2 + 1);
+    let rest = if path.len() % 2 == 1 {
+        encoded.push(flag | 0x10 | path[0]);
+        &path[1..]
+    } else {
+        encoded.push(flag);
+        path
+    };
+    encoded.extend(rest.chunks(2).map(|pair| (pair[0] << 4) | pair[1]));
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use akula::models::EMPTY_HASH;
+    use ethers::utils::hex;
+    use std::collections::BTreeMap;
+
+    fn bytes(s: &str) -> Vec<u8> {
+        hex::decode(s).unwrap()
+    }
+
+    fn hash(s: &str) -> H256 {
+        H256::from_slice(&bytes(s))
+    }
+
+    fn key(prefix: &[u8], fill: u8) -> H256 {
+        let mut key = H256::repeat_byte(fill);
+        key.0[..prefix.len()].copy_from_slice(prefix);
+        key
+    }
+
+    fn build(leaves: &[(H256, Vec<u8>)], targets: &[H256]) -> TrieProof {
+        ProofBuilder::new(targets)
+            .build(leaves.iter().cloned().map(Ok))
+            .unwrap()
+    }
+
+    #[test]
+    fn empty_trie() {
+        let trie = build(&[], &[H256::zero()]);
+        assert_eq!(
+            trie.root,
+            hash("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421")
+        );
+        assert_eq!(trie.root, empty_root());
+        assert_eq!(trie.proofs, vec![Vec::<Vec<u8>>::new()]);
+    }
+
+    #[test]
+    fn single_leaf() {
+        let target = key(&[0x12, 0x34], 0x56);
+        let trie = build(&[(target, storage_leaf(U256::ONE))], &[target]);
+        assert_eq!(
+            trie.root,
+            hash("6a47d8d2284ea3f504b51e38fd2c490b04bff5c5bcaddfb78fba4518227e90d5")
+        );
+        assert_eq!(
+            trie.proofs,
+            vec![vec![bytes(
+                "e3a120123456565656565656565656565656565656565656565656565656565656565601"
+            )]]
+        );
+    }
+
+    #[test]
+    fn extension_node() {
+        let (a, b) = (key(&[0x12, 0x34], 0x11), key(&[0x12, 0x35], 0x22));
+        let missing = key(&[0x12, 0x36], 0x33);
+        let trie = build(
+            &[
+                (a, storage_leaf(U256::new(0xdeadbeef))),
+                (b, storage_leaf(U256::new(0x2a))),
+            ],
+            &[a, missing],
+        );
+
+        let extension =
+            bytes("e4821123a0c491901c66a475e99ec2e5cb98f05f478922544196f708f0d0d2fe1f91b1a160");
+        let branch = bytes(
+            "f85180808080a0412eb9663ed76c13a67ec3b77006b8c04485315cd45aa0440c5a230c94e07ac7a006\
+             85ebb406f17cfd75fb9a3a8d055aace26d2e64881c6954fd6c7be0f33ab4678080808080808080808080",
+        );
+        let leaf =
+            bytes("e69f201111111111111111111111111111111111111111111111111111111111118584deadbeef");
+        assert_eq!(
+            trie.root,
+            hash("dc5804e36b943b6f459105249442d8b45c160af189c4f33f784b08be51cf440f")
+        );
+        assert_eq!(
+            trie.proofs,
+            vec![
+                vec![extension.clone(), branch.clone(), leaf],
+                // The proof of a missing key ends at the branch its path leaves the trie.
+                vec![extension, branch],
+            ]
+        );
+    }
+
+    #[test]
+    fn inlined_short_nodes() {
+        // Keys differing in their last nibble only leave leaves too short to be hashed,
+        // and their branch is inlined into the extension above it.
+        let (a, b) = (H256::zero(), H256::from_low_u64_be(1));
+        let trie = build(
+            &[
+                (a, storage_leaf(U256::ONE)),
+                (b, storage_leaf(U256::new(2))),
+            ],
+            &[b],
+        );
+        assert_eq!(
+            trie.root,
+            hash("4052e8ec13706311171e65d44aad5e31bec5e29310b06a3dfa92767947cb0d79")
+        );
+        assert_eq!(
+            trie.proofs,
+            vec![vec![bytes(
+                "f7a01000000000000000000000000000000000000000000000000000000000000000d5c22001c2\
+                 2002808080808080808080808080808080"
+            )]]
+        );
+    }
+
+    #[test]
+    fn account_proof() {
+        let rich = Account {
+            nonce: 1,
+            balance: U256::new(1_000_000_000_000_000_000),
+            code_hash: EMPTY_HASH,
+        };
+        let poor = Account {
+            nonce: 0,
+            balance: U256::new(0x2a),
+            code_hash: EMPTY_HASH,
+        };
+        let leaf = account_leaf(&rich, empty_root());
+        assert_eq!(
+            leaf,
+            bytes(
+                "f84c01880de0b6b3a7640000a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc00162\
+                 2fb5e363b421a0c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+            )
+        );
+
+        let (a, b) = (keccak256([0x11u8; 20]), keccak256([0x22; 20]));
+        let mut leaves = vec![(a, leaf.clone()), (b, account_leaf(&poor, empty_root()))];
+        leaves.sort();
+        let trie = build(&leaves, &[a]);
+        assert_eq!(
+            trie.root,
+            hash("fd18785323e82d918314839f13030963b10e0ca7a5ad8b9516b90fa2aaf2adbc")
+        );
+        assert_eq!(
+            trie.proofs,
+            vec![vec![
+                bytes(
+                    "f8518080a0df73185796dbdce8057722b2412aca219cfd5ed407c19921252be5248de18a76\
+                     8080808080808080808080a045054049ffb7ab094b83374bb3dfdf44e582dad39da54f6342\
+                     f2d41915192b668080"
+                ),
+                [
+                    bytes("f871a032c07404b8c1df4c46226425cac68c28d27a766bbddce62309f36724839b22c0b84e"),
+                    leaf,
+                ]
+                .concat(),
+            ]]
+        );
+    }
+
+    #[test]
+    fn hex_prefix_encoding() {
+        assert_eq!(hex_prefix(&[1, 2, 3], false), vec![0x11, 0x23]);
+        assert_eq!(hex_prefix(&[0, 1, 2, 3], false), vec![0x00, 0x01, 0x23]);
+        assert_eq!(
+            hex_prefix(&[0x0f, 1, 0x0c, 0x0b, 8], true),
+            vec![0x3f, 0x1c, 0xb8]
+        );
+        assert_eq!(hex_prefix(&[], true), vec![0x20]);
+    }
+
+    #[test]
+    fn prefix_bounds_of_nibbles() {
+        assert_eq!(prefix_bounds(&[]), (H256::zero(), H256::repeat_byte(0xff)));
+        assert_eq!(
+            prefix_bounds(&[1, 2, 3]),
+            (key(&[0x12, 0x30], 0x00), key(&[0x12, 0x3f], 0xff))
+        );
+    }
+
+    #[test]
+    fn overlaid_entries() {
+        let base = vec![
+            (H256::from_low_u64_be(1), 1),
+            (H256::from_low_u64_be(2), 2),
+            (H256::from_low_u64_be(4), 4),
+        ];
+        let overlay = vec![
+            (H256::from_low_u64_be(0), None),
+            (H256::from_low_u64_be(2), None),
+            (H256::from_low_u64_be(3), Some(3)),
+            (H256::from_low_u64_be(4), Some(5)),
+            (H256::from_low_u64_be(6), Some(6)),
+        ];
+        let merged = Overlaid::new(base.into_iter().map(Ok), overlay.into_iter().map(Ok))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            merged,
+            vec![
+                (H256::from_low_u64_be(1), 1),
+                (H256::from_low_u64_be(3), 3),
+                (H256::from_low_u64_be(4), 5),
+                (H256::from_low_u64_be(6), 6),
+            ]
+        );
+    }
+
+    /// Head trie keeping a branch node with the hashes of its hashed children at every
+    /// branch, like the intermediate hashes tables.
+    struct MemoryTrie {
+        head: BTreeMap<H256, Vec<u8>>,
+        branches: BTreeMap<Vec<u8>, StoredBranch>,
+        changes: BTreeMap<H256, Option<Vec<u8>>>,
+    }
+
+    impl MemoryTrie {
+        fn new(head: BTreeMap<H256, Vec<u8>>, changes: BTreeMap<H256, Option<Vec<u8>>>) -> Self {
+            let mut trie = Self {
+                head,
+                branches: BTreeMap::new(),
+                changes,
+            };
+            trie.store_branches(&mut vec![]);
+            trie
+        }
+
+        fn head_leaves(&self, prefix: &[u8]) -> Vec<(H256, Vec<u8>)> {
+            let (low, high) = prefix_bounds(prefix);
+            self.head
+                .range(low..=high)
+                .map(|(key, value)| (*key, value.clone()))
+                .collect()
+        }
+
+        fn store_branches(&mut self, prefix: &mut Vec<u8>) {
+            let leaves = self.head_leaves(prefix);
+            if leaves.len() < 2 {
+                return;
+            }
+            let mut nibbles = leaves
+                .iter()
+                .map(|(key, _)| to_nibbles(key)[prefix.len()])
+                .collect::<Vec<_>>();
+            nibbles.dedup();
+
+            if let &[nibble] = nibbles.as_slice() {
+                prefix.push(nibble);
+                self.store_branches(prefix);
+                prefix.pop();
+                return;
+            }
+
+            let mut branch = StoredBranch {
+                state_mask: 0,
+                hash_mask: 0,
+                hashes: vec![],
+            };
+            for nibble in nibbles {
+                prefix.push(nibble);
+                branch.state_mask |= 1 << nibble;
+
+                let mut builder = ProofBuilder::new(&[]);
+                let mut leaves = self.head_leaves(prefix).into_iter().map(Ok).peekable();
+                let subtrie = builder.subtrie(prefix, &mut leaves).unwrap();
+                if let NodeRef::Hash(hash) = builder.node_ref(prefix, subtrie) {
+                    branch.hash_mask |= 1 << nibble;
+                    branch.hashes.push(hash);
+                }
+
+                self.store_branches(prefix);
+                prefix.pop();
+            }
+            self.branches.insert(prefix.clone(), branch);
+        }
+
+        fn overlaid(&self) -> Vec<(H256, Vec<u8>)> {
+            Overlaid::new(
+                self.head.clone().into_iter().map(Ok),
+                self.changes.clone().into_iter().map(Ok),
+            )
+            .collect::<anyhow::Result<_>>()
+            .unwrap()
+        }
+    }
+
+    impl TrieSource for MemoryTrie {
+        fn branch(&mut self, prefix: &[u8]) -> anyhow::Result<Option<StoredBranch>> {
+            Ok(self.branches.get(prefix).cloned())
+        }
+
+        fn first_branch_below(&mut self, prefix: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+            Ok(self
+                .branches
+                .range(prefix.to_vec()..)
+                .map(|(path, _)| path)
+                .find(|path| path.as_slice() != prefix)
+                .filter(|path| path.starts_with(prefix))
+                .cloned())
+        }
+
+        fn changed(&self, prefix: &[u8]) -> bool {
+            let (low, high) = prefix_bounds(prefix);
+            self.changes.range(low..=high).next().is_some()
+        }
+
+        fn leaves(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<(H256, Vec<u8>)>> {
+            let (low, high) = prefix_bounds(prefix);
+            Overlaid::new(
+                self.head_leaves(prefix).into_iter().map(Ok),
+                self.changes
+                    .range(low..=high)
+                    .map(|(key, value)| Ok((*key, value.clone()))),
+            )
+            .collect()
+        }
+    }
+
+    fn assert_builds_like_leaves(mut trie: MemoryTrie, targets: &[H256]) {
+        let expected = build(&trie.overlaid(), targets);
+        let built = ProofBuilder::new(targets).build_from(&mut trie).unwrap();
+        assert_eq!(built.root, expected.root);
+        assert_eq!(built.proofs, expected.proofs);
+    }
+
+    #[test]
+    fn stored_branches_with_changes() {
+        let head = (0..200u64)
+            .map(|i| (keccak256(i.to_be_bytes()), storage_leaf(U256::from(i + 1))))
+            .collect::<BTreeMap<_, _>>();
+        let changes = [
+            (
+                keccak256(3u64.to_be_bytes()),
+                Some(storage_leaf(U256::new(42))),
+            ),
+            (keccak256(7u64.to_be_bytes()), None),
+            (
+                keccak256(1000u64.to_be_bytes()),
+                Some(storage_leaf(U256::ONE)),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let targets = [3, 7, 50, 2000]
+            .map(|i: u64| keccak256(i.to_be_bytes()))
+            .to_vec();
+
+        assert_builds_like_leaves(MemoryTrie::new(head.clone(), BTreeMap::new()), &targets);
+        assert_builds_like_leaves(MemoryTrie::new(head.clone(), changes), &targets);
+        assert_builds_like_leaves(MemoryTrie::new(head, BTreeMap::new()), &[]);
+    }
+
+    #[test]
+    fn stored_branches_collapsing_into_hashed_child() {
+        // Deleting the leaf under nibble 2 leaves the root with the branch under nibble 1,
+        // whose stored hash cannot be used once it is merged into the root.
+        let head = [
+            (key(&[0x10], 0x01), storage_leaf(U256::ONE)),
+            (key(&[0x11], 0x02), storage_leaf(U256::new(2))),
+            (key(&[0x20], 0x03), storage_leaf(U256::new(3))),
+        ]
+        .into_iter()
+        .collect();
+        let changes = [(key(&[0x20], 0x03), None)].into_iter().collect();
+
+        assert_builds_like_leaves(MemoryTrie::new(head, changes), &[]);
+    }
+}
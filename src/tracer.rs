@@ -0,0 +1,1258 @@
+use akula::{
+    execution::{
+        evm::{ExecutionState, OpCode, Output, StatusCode},
+        tracer::{CallKind, MessageKind, Tracer},
+    },
+    models::*,
+};
+use ethers::types as ethers_types;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+};
+
+use crate::utils;
+
+fn u256_to_address(value: &U256) -> Address {
+    Address::from_slice(&value.to_be_bytes()[12..])
+}
+
+/// Records every address and storage slot touched during execution, the way geth's
+/// access list tracer does: the sender, the recipient and precompiles are only listed
+/// when one of their storage slots is accessed.
+#[derive(Debug)]
+pub struct AccessListTracer {
+    excluded: HashSet<Address>,
+    access_list: BTreeMap<Address, BTreeSet<H256>>,
+    /// Storage contexts of the active call frames.
+    contexts: Vec<Address>,
+}
+
+impl AccessListTracer {
+    pub fn new(
+        access_list: &[AccessListItem],
+        excluded: impl IntoIterator<Item = Address>,
+    ) -> Self {
+        let excluded = excluded.into_iter().collect::<HashSet<_>>();
+        let mut tracer = Self {
+            excluded,
+            access_list: BTreeMap::new(),
+            contexts: vec![],
+        };
+        for item in access_list {
+            tracer.add_address(item.address);
+            for slot in &item.slots {
+                tracer.add_slot(item.address, *slot);
+            }
+        }
+        tracer
+    }
+
+    pub fn access_list(&self) -> Vec<AccessListItem> {
+        self.access_list
+            .iter()
+            .map(|(address, slots)| AccessListItem {
+                address: *address,
+                slots: slots.iter().copied().collect(),
+            })
+            .collect()
+    }
+
+    /// Whether both tracers recorded the same accounts and slots.
+    pub fn same_access_list(&self, other: &Self) -> bool {
+        self.access_list == other.access_list
+    }
+
+    fn add_address(&mut self, address: Address) {
+        if !self.excluded.contains(&address) {
+            self.access_list.entry(address).or_default();
+        }
+    }
+
+    fn add_slot(&mut self, address: Address, slot: H256) {
+        self.access_list.entry(address).or_default().insert(slot);
+    }
+}
+
+impl Tracer for AccessListTracer {
+    fn trace_instructions(&self) -> bool {
+        true
+    }
+
+    fn capture_start(
+        &mut self,
+        _depth: u16,
+        _sender: Address,
+        recipient: Address,
+        _real_sender: Address,
+        _code_address: Address,
+        _call_type: MessageKind,
+        _input: Bytes,
+        _gas: u64,
+        _value: U256,
+    ) {
+        self.contexts.push(recipient);
+    }
+
+    fn capture_state(
+        &mut self,
+        env: &ExecutionState,
+        _pc: usize,
+        op: OpCode,
+        _cost: u64,
+        _depth: u16,
+    ) {
+        match op {
+            OpCode::SLOAD | OpCode::SSTORE => {
+                if let Some(context) = self.contexts.last().copied() {
+                    self.add_slot(context, H256(env.stack.get(0).to_be_bytes()));
+                }
+            }
+            OpCode::EXTCODECOPY
+            | OpCode::EXTCODEHASH
+            | OpCode::EXTCODESIZE
+            | OpCode::BALANCE
+            | OpCode::SELFDESTRUCT => {
+                self.add_address(u256_to_address(env.stack.get(0)));
+            }
+            OpCode::CALL | OpCode::CALLCODE | OpCode::DELEGATECALL | OpCode::STATICCALL => {
+                self.add_address(u256_to_address(env.stack.get(1)));
+            }
+            _ => {}
+        }
+    }
+
+    fn capture_end(&mut self, _depth: usize, _start_gas: u64, _output: &Output) {
+        self.contexts.pop();
+    }
+}
+
+/// Error message Parity reports for a failed call frame.
+pub fn parity_error(status_code: &StatusCode) -> String {
+    match status_code {
+        StatusCode::Revert => "Reverted".to_string(),
+        StatusCode::OutOfGas => "Out of gas".to_string(),
+        StatusCode::BadJumpDestination => "Bad jump destination".to_string(),
+        StatusCode::InvalidInstruction | StatusCode::UndefinedInstruction => {
+            "Bad instruction".to_string()
+        }
+        StatusCode::StackUnderflow => "Stack underflow".to_string(),
+        StatusCode::StackOverflow | StatusCode::CallDepthExceeded => "Out of stack".to_string(),
+        StatusCode::StaticModeViolation => "Mutable Call In Static Context".to_string(),
+        other => format!("{other:?}"),
+    }
+}
+
+/// Error message geth reports for a failed call frame.
+pub fn geth_error(status_code: &StatusCode) -> String {
+    match status_code {
+        StatusCode::Revert => "execution reverted".to_string(),
+        StatusCode::OutOfGas => "out of gas".to_string(),
+        StatusCode::BadJumpDestination => "invalid jump destination".to_string(),
+        // The designated invalid instruction (0xFE) is named; undefined opcodes are not.
+        StatusCode::InvalidInstruction => "invalid opcode: INVALID".to_string(),
+        StatusCode::UndefinedInstruction => "invalid opcode".to_string(),
+        StatusCode::StackUnderflow => "stack underflow".to_string(),
+        StatusCode::StackOverflow => "stack limit reached".to_string(),
+        StatusCode::CallDepthExceeded => "max call depth exceeded".to_string(),
+        StatusCode::StaticModeViolation => "write protection".to_string(),
+        other => format!("{other:?}"),
+    }
+}
+
+/// Attaches transaction and block coordinates to a transaction's traces.
+pub fn localize_traces(
+    traces: Vec<ethers_types::TransactionTrace>,
+    transaction_position: usize,
+    transaction_hash: H256,
+    block_number: BlockNumber,
+    block_hash: H256,
+) -> Vec<ethers_types::Trace> {
+    traces
+        .into_iter()
+        .map(|trace| ethers_types::Trace {
+            action: trace.action,
+            result: trace.result,
+            trace_address: trace.trace_address,
+            subtraces: trace.subtraces,
+            transaction_position: Some(transaction_position),
+            transaction_hash: Some(transaction_hash),
+            block_number: block_number.0,
+            block_hash,
+            action_type: trace.action_type,
+            error: trace.error,
+        })
+        .collect()
+}
+
+/// Parity-style trace of a block or ommer reward.
+pub fn reward_trace(
+    author: Address,
+    value: U256,
+    reward_type: ethers_types::RewardType,
+    block_number: BlockNumber,
+    block_hash: H256,
+) -> ethers_types::Trace {
+    ethers_types::Trace {
+        action: ethers_types::Action::Reward(ethers_types::Reward {
+            author,
+            value: utils::ethnum_u256_to_ethers(&value),
+            reward_type,
+        }),
+        result: None,
+        trace_address: vec![],
+        subtraces: 0,
+        transaction_position: None,
+        transaction_hash: None,
+        block_number: block_number.0,
+        block_hash,
+        action_type: ethers_types::ActionType::Reward,
+        error: None,
+    }
+}
+
+/// Builds Parity-style call traces, one list of frames per executed transaction.
+#[derive(Debug, Default)]
+pub struct ParityTracer {
+    transactions: Vec<Vec<ethers_types::TransactionTrace>>,
+    /// Indices of the open frames in the current transaction's trace list.
+    frames: Vec<usize>,
+    /// Created addresses of the open create frames.
+    created: Vec<Option<Address>>,
+    /// Return data of every executed transaction.
+    outputs: Vec<Bytes>,
+}
+
+impl ParityTracer {
+    /// Traces of every transaction executed so far, in execution order.
+    pub fn into_transactions(self) -> Vec<Vec<ethers_types::TransactionTrace>> {
+        self.transactions
+    }
+
+    /// Return data of every transaction executed so far, in execution order.
+    pub fn outputs(&self) -> &[Bytes] {
+        &self.outputs
+    }
+
+    fn child_trace_address(&mut self) -> Vec<usize> {
+        match self.frames.last() {
+            Some(parent) => {
+                let parent = &mut self.transactions.last_mut().unwrap()[*parent];
+                let mut trace_address = parent.trace_address.clone();
+                trace_address.push(parent.subtraces);
+                parent.subtraces += 1;
+                trace_address
+            }
+            None => vec![],
+        }
+    }
+}
+
+impl Tracer for ParityTracer {
+    fn capture_start(
+        &mut self,
+        _depth: u16,
+        sender: Address,
+        recipient: Address,
+        real_sender: Address,
+        code_address: Address,
+        call_type: MessageKind,
+        input: Bytes,
+        gas: u64,
+        value: U256,
+    ) {
+        if self.frames.is_empty() {
+            self.transactions.push(vec![]);
+        }
+
+        let (action, action_type, created) = match call_type {
+            MessageKind::Create { .. } => (
+                ethers_types::Action::Create(ethers_types::Create {
+                    from: sender,
+                    value: utils::ethnum_u256_to_ethers(&value),
+                    gas: ethers_types::U256::from(gas),
+                    init: input.into(),
+                }),
+                ethers_types::ActionType::Create,
+                Some(recipient),
+            ),
+            MessageKind::Call { call_kind, .. } => (
+                ethers_types::Action::Call(ethers_types::Call {
+                    from: real_sender,
+                    to: code_address,
+                    value: utils::ethnum_u256_to_ethers(&value),
+                    gas: ethers_types::U256::from(gas),
+                    input: input.into(),
+                    call_type: match call_kind {
+                        CallKind::Call => ethers_types::CallType::Call,
+                        CallKind::CallCode => ethers_types::CallType::CallCode,
+                        CallKind::DelegateCall => ethers_types::CallType::DelegateCall,
+                        CallKind::StaticCall => ethers_types::CallType::StaticCall,
+                    },
+                }),
+                ethers_types::ActionType::Call,
+                None,
+            ),
+        };
+
+        let trace_address = self.child_trace_address();
+        let traces = self.transactions.last_mut().unwrap();
+        self.frames.push(traces.len());
+        self.created.push(created);
+        traces.push(ethers_types::TransactionTrace {
+            trace_address,
+            subtraces: 0,
+            action,
+            action_type,
+            result: None,
+            error: None,
+        });
+    }
+
+    fn capture_end(&mut self, _depth: usize, start_gas: u64, output: &Output) {
+        let (frame, created) = match (self.frames.pop(), self.created.pop()) {
+            (Some(frame), Some(created)) => (frame, created),
+            _ => return,
+        };
+        let trace = &mut self.transactions.last_mut().unwrap()[frame];
+
+        if output.status_code == StatusCode::Success {
+            let gas_used = ethers_types::U256::from(start_gas - output.gas_left as u64);
+            let output_data = ethers_types::Bytes::from(output.output_data.clone());
+            trace.result = Some(match created {
+                Some(address) => ethers_types::Res::Create(ethers_types::CreateResult {
+                    gas_used,
+                    code: output_data,
+                    address,
+                }),
+                None => ethers_types::Res::Call(ethers_types::CallResult {
+                    gas_used,
+                    output: output_data,
+                }),
+            });
+        } else {
+            trace.error = Some(parity_error(&output.status_code));
+        }
+
+        if self.frames.is_empty() {
+            self.outputs.push(output.output_data.clone());
+        }
+    }
+
+    fn capture_self_destruct(&mut self, caller: Address, beneficiary: Address, balance: U256) {
+        if self.frames.is_empty() {
+            return;
+        }
+
+        let trace_address = self.child_trace_address();
+        self.transactions
+            .last_mut()
+            .unwrap()
+            .push(ethers_types::TransactionTrace {
+                trace_address,
+                subtraces: 0,
+                action: ethers_types::Action::Suicide(ethers_types::Suicide {
+                    address: caller,
+                    refund_address: beneficiary,
+                    balance: utils::ethnum_u256_to_ethers(&balance),
+                }),
+                action_type: ethers_types::ActionType::Suicide,
+                result: None,
+                error: None,
+            });
+    }
+}
+
+/// Which parts of the EVM state `StructLogger` copies into every step.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StructLoggerConfig {
+    pub disable_memory: bool,
+    pub disable_stack: bool,
+    pub disable_storage: bool,
+}
+
+/// Records geth-style struct logs, one entry per executed opcode.
+#[derive(Debug)]
+pub struct StructLogger {
+    config: StructLoggerConfig,
+    /// Top-level frames to let through before recording, used to skip the transactions
+    /// that precede the traced one in a block.
+    skip: usize,
+    recording: bool,
+    logs: Vec<ethers_types::StructLog>,
+    /// Storage contexts of the active call frames.
+    contexts: Vec<Address>,
+    storage: HashMap<Address, BTreeMap<ethers_types::H256, ethers_types::H256>>,
+    /// Log of an `SLOAD` waiting for the loaded value, which is only on the stack at the next step.
+    pending_sload: Option<(usize, Address, H256)>,
+    output: Option<Bytes>,
+}
+
+impl StructLogger {
+    pub fn new(config: StructLoggerConfig) -> Self {
+        Self::skipping(config, 0)
+    }
+
+    /// A logger that only records the transaction following the first `skip` ones.
+    pub fn skipping(config: StructLoggerConfig, skip: usize) -> Self {
+        Self {
+            config,
+            skip,
+            recording: false,
+            logs: vec![],
+            contexts: vec![],
+            storage: HashMap::new(),
+            pending_sload: None,
+            output: None,
+        }
+    }
+
+    /// Recorded logs and the output of the recorded top-level call.
+    pub fn into_parts(self) -> (Vec<ethers_types::StructLog>, Bytes) {
+        (self.logs, self.output.unwrap_or_default())
+    }
+
+    /// Completes the pending `SLOAD` log with the loaded value, or drops it if the load failed.
+    fn resolve_sload(&mut self, value: Option<&U256>) {
+        if let (Some((index, address, slot)), Some(value)) = (self.pending_sload.take(), value) {
+            let storage = self.storage.entry(address).or_default();
+            storage.insert(slot, H256(value.to_be_bytes()));
+            self.logs[index].storage = Some(storage.clone());
+        }
+    }
+}
+
+impl Tracer for StructLogger {
+    fn trace_instructions(&self) -> bool {
+        true
+    }
+
+    fn capture_start(
+        &mut self,
+        depth: u16,
+        _sender: Address,
+        recipient: Address,
+        _real_sender: Address,
+        _code_address: Address,
+        _call_type: MessageKind,
+        _input: Bytes,
+        _gas: u64,
+        _value: U256,
+    ) {
+        if depth == 0 {
+            self.recording = self.skip == 0 && self.output.is_none();
+            self.skip = self.skip.saturating_sub(1);
+        }
+        if self.recording {
+            self.resolve_sload(None);
+            self.contexts.push(recipient);
+        }
+    }
+
+    fn capture_state(
+        &mut self,
+        env: &ExecutionState,
+        pc: usize,
+        op: OpCode,
+        cost: u64,
+        depth: u16,
+    ) {
+        if !self.recording {
+            return;
+        }
+
+        let loaded = self.pending_sload.is_some().then(|| env.stack.get(0));
+        self.resolve_sload(loaded);
+
+        let context = self.contexts.last().copied().unwrap_or_default();
+        let storage = if self.config.disable_storage {
+            None
+        } else {
+            match op {
+                OpCode::SLOAD => {
+                    self.pending_sload = Some((
+                        self.logs.len(),
+                        context,
+                        H256(env.stack.get(0).to_be_bytes()),
+                    ));
+                    None
+                }
+                OpCode::SSTORE => {
+                    let storage = self.storage.entry(context).or_default();
+                    storage.insert(
+                        H256(env.stack.get(0).to_be_bytes()),
+                        H256(env.stack.get(1).to_be_bytes()),
+                    );
+                    Some(storage.clone())
+                }
+                _ => None,
+            }
+        };
+
+        self.logs.push(ethers_types::StructLog {
+            depth: u64::from(depth) + 1,
+            error: None,
+            gas: env.gas_left as u64,
+            gas_cost: cost,
+            memory: (!self.config.disable_memory).then(|| {
+                env.memory
+                    .chunks(32)
+                    .map(|word| {
+                        word.iter()
+                            .map(|byte| format!("{byte:02x}"))
+                            .collect::<String>()
+                    })
+                    .collect()
+            }),
+            op: op.name().to_string(),
+            pc: pc as u64,
+            refund_counter: None,
+            stack: (!self.config.disable_stack).then(|| {
+                (0..env.stack.len())
+                    .rev()
+                    .map(|i| utils::ethnum_u256_to_ethers(env.stack.get(i)))
+                    .collect()
+            }),
+            storage,
+        });
+    }
+
+    fn capture_end(&mut self, depth: usize, _start_gas: u64, output: &Output) {
+        if !self.recording {
+            return;
+        }
+
+        self.resolve_sload(None);
+        self.contexts.pop();
+        if output.status_code != StatusCode::Success {
+            if let Some(log) = self
+                .logs
+                .last_mut()
+                .filter(|log| log.depth == depth as u64 + 1)
+            {
+                log.error = Some(geth_error(&output.status_code));
+            }
+        }
+        if depth == 0 {
+            self.output = Some(output.output_data.clone());
+            self.recording = false;
+        }
+    }
+}
+
+/// Forwards every event to each of the wrapped tracers.
+pub struct MultiTracer<'a>(pub Vec<&'a mut dyn Tracer>);
+
+impl Tracer for MultiTracer<'_> {
+    fn trace_instructions(&self) -> bool {
+        self.0.iter().any(|tracer| tracer.trace_instructions())
+    }
+
+    fn capture_start(
+        &mut self,
+        depth: u16,
+        sender: Address,
+        recipient: Address,
+        real_sender: Address,
+        code_address: Address,
+        call_type: MessageKind,
+        input: Bytes,
+        gas: u64,
+        value: U256,
+    ) {
+        for tracer in &mut self.0 {
+            tracer.capture_start(
+                depth,
+                sender,
+                recipient,
+                real_sender,
+                code_address,
+                call_type,
+                input.clone(),
+                gas,
+                value,
+            );
+        }
+    }
+
+    fn capture_state(
+        &mut self,
+        env: &ExecutionState,
+        pc: usize,
+        op: OpCode,
+        cost: u64,
+        depth: u16,
+    ) {
+        for tracer in &mut self.0 {
+            if tracer.trace_instructions() {
+                tracer.capture_state(env, pc, op, cost, depth);
+            }
+        }
+    }
+
+    fn capture_end(&mut self, depth: usize, start_gas: u64, output: &Output) {
+        for tracer in &mut self.0 {
+            tracer.capture_end(depth, start_gas, output);
+        }
+    }
+
+    fn capture_self_destruct(&mut self, caller: Address, beneficiary: Address, balance: U256) {
+        for tracer in &mut self.0 {
+            tracer.capture_self_destruct(caller, beneficiary, balance);
+        }
+    }
+}
+
+/// Forwards every event to a tracer that stays readable while the executor holds this, so
+/// that what it recorded can be taken between transactions.
+pub struct SharedTracer<'a, T>(pub &'a RefCell<T>);
+
+impl<T: Tracer> Tracer for SharedTracer<'_, T> {
+    fn trace_instructions(&self) -> bool {
+        self.0.borrow().trace_instructions()
+    }
+
+    fn capture_start(
+        &mut self,
+        depth: u16,
+        sender: Address,
+        recipient: Address,
+        real_sender: Address,
+        code_address: Address,
+        call_type: MessageKind,
+        input: Bytes,
+        gas: u64,
+        value: U256,
+    ) {
+        self.0.borrow_mut().capture_start(
+            depth,
+            sender,
+            recipient,
+            real_sender,
+            code_address,
+            call_type,
+            input,
+            gas,
+            value,
+        );
+    }
+
+    fn capture_state(
+        &mut self,
+        env: &ExecutionState,
+        pc: usize,
+        op: OpCode,
+        cost: u64,
+        depth: u16,
+    ) {
+        self.0.borrow_mut().capture_state(env, pc, op, cost, depth);
+    }
+
+    fn capture_end(&mut self, depth: usize, start_gas: u64, output: &Output) {
+        self.0.borrow_mut().capture_end(depth, start_gas, output);
+    }
+
+    fn capture_self_destruct(&mut self, caller: Address, beneficiary: Address, balance: U256) {
+        self.0
+            .borrow_mut()
+            .capture_self_destruct(caller, beneficiary, balance);
+    }
+}
+
+/// Accounts and storage slots a transaction may have changed.
+#[derive(Debug, Default)]
+pub struct TouchedState {
+    pub accounts: BTreeSet<Address>,
+    pub storage: BTreeMap<Address, BTreeSet<H256>>,
+}
+
+/// Records the accounts and storage slots touched by each transaction, which are the
+/// only ones whose state can differ before and after it.
+#[derive(Debug, Default)]
+pub struct StateDiffTracer {
+    transactions: Vec<TouchedState>,
+    /// Storage contexts of the active call frames.
+    contexts: Vec<Address>,
+}
+
+impl StateDiffTracer {
+    /// Touched state of every transaction executed so far, in execution order.
+    pub fn into_transactions(self) -> Vec<TouchedState> {
+        self.transactions
+    }
+
+    /// Touched state of the transaction executed last, which is no longer kept.
+    pub fn take_last(&mut self) -> TouchedState {
+        self.transactions.pop().unwrap_or_default()
+    }
+}
+
+impl Tracer for StateDiffTracer {
+    fn trace_instructions(&self) -> bool {
+        true
+    }
+
+    fn capture_start(
+        &mut self,
+        depth: u16,
+        sender: Address,
+        recipient: Address,
+        _real_sender: Address,
+        _code_address: Address,
+        _call_type: MessageKind,
+        _input: Bytes,
+        _gas: u64,
+        _value: U256,
+    ) {
+        if depth == 0 {
+            self.transactions.push(TouchedState::default());
+        }
+        if let Some(touched) = self.transactions.last_mut() {
+            touched.accounts.insert(sender);
+            touched.accounts.insert(recipient);
+        }
+        self.contexts.push(recipient);
+    }
+
+    fn capture_state(
+        &mut self,
+        env: &ExecutionState,
+        _pc: usize,
+        op: OpCode,
+        _cost: u64,
+        _depth: u16,
+    ) {
+        if op == OpCode::SSTORE {
+            if let (Some(touched), Some(context)) =
+                (self.transactions.last_mut(), self.contexts.last())
+            {
+                touched
+                    .storage
+                    .entry(*context)
+                    .or_default()
+                    .insert(H256(env.stack.get(0).to_be_bytes()));
+            }
+        }
+    }
+
+    fn capture_end(&mut self, _depth: usize, _start_gas: u64, _output: &Output) {
+        self.contexts.pop();
+    }
+
+    fn capture_self_destruct(&mut self, caller: Address, beneficiary: Address, _balance: U256) {
+        if let Some(touched) = self.transactions.last_mut() {
+            touched.accounts.insert(caller);
+            touched.accounts.insert(beneficiary);
+        }
+    }
+}
+
+/// Number of stack items an instruction leaves on top of the stack that Parity reports
+/// in its VM traces.
+fn pushed_items(op: OpCode) -> usize {
+    match op {
+        OpCode::DUP1
+        | OpCode::DUP2
+        | OpCode::DUP3
+        | OpCode::DUP4
+        | OpCode::DUP5
+        | OpCode::DUP6
+        | OpCode::DUP7
+        | OpCode::DUP8
+        | OpCode::DUP9
+        | OpCode::DUP10
+        | OpCode::DUP11
+        | OpCode::DUP12
+        | OpCode::DUP13
+        | OpCode::DUP14
+        | OpCode::DUP15
+        | OpCode::DUP16 => usize::from(op.to_u8() - OpCode::DUP1.to_u8()) + 2,
+        OpCode::SWAP1
+        | OpCode::SWAP2
+        | OpCode::SWAP3
+        | OpCode::SWAP4
+        | OpCode::SWAP5
+        | OpCode::SWAP6
+        | OpCode::SWAP7
+        | OpCode::SWAP8
+        | OpCode::SWAP9
+        | OpCode::SWAP10
+        | OpCode::SWAP11
+        | OpCode::SWAP12
+        | OpCode::SWAP13
+        | OpCode::SWAP14
+        | OpCode::SWAP15
+        | OpCode::SWAP16 => usize::from(op.to_u8() - OpCode::SWAP1.to_u8()) + 2,
+        OpCode::STOP
+        | OpCode::POP
+        | OpCode::MSTORE
+        | OpCode::MSTORE8
+        | OpCode::SSTORE
+        | OpCode::JUMP
+        | OpCode::JUMPI
+        | OpCode::JUMPDEST
+        | OpCode::CALLDATACOPY
+        | OpCode::CODECOPY
+        | OpCode::EXTCODECOPY
+        | OpCode::RETURNDATACOPY
+        | OpCode::LOG0
+        | OpCode::LOG1
+        | OpCode::LOG2
+        | OpCode::LOG3
+        | OpCode::LOG4
+        | OpCode::RETURN
+        | OpCode::REVERT
+        | OpCode::INVALID
+        | OpCode::SELFDESTRUCT => 0,
+        _ => 1,
+    }
+}
+
+/// A stack word used as a memory offset or length, or `None` past 4 GiB, which no
+/// transaction can pay to expand memory to, so the instruction runs out of gas instead.
+fn memory_word(word: &U256) -> Option<usize> {
+    (*word <= U256::from(u32::MAX)).then(|| word.as_usize())
+}
+
+/// Memory range an instruction writes to, read from the stack before it executes.
+fn written_memory(env: &ExecutionState, op: OpCode) -> Option<(usize, usize)> {
+    let range = |offset: &U256, len: &U256| Some((memory_word(offset)?, memory_word(len)?));
+    match op {
+        OpCode::MSTORE => range(env.stack.get(0), &U256::from(32_u8)),
+        OpCode::MSTORE8 => range(env.stack.get(0), &U256::ONE),
+        OpCode::CALLDATACOPY | OpCode::CODECOPY | OpCode::RETURNDATACOPY => {
+            range(env.stack.get(0), env.stack.get(2))
+        }
+        OpCode::EXTCODECOPY => range(env.stack.get(1), env.stack.get(3)),
+        _ => None,
+    }
+    .filter(|(_, len)| *len > 0)
+}
+
+/// Instruction of a VM trace frame whose results are only known once it has executed.
+#[derive(Debug)]
+struct PendingOp {
+    op: OpCode,
+    memory: Option<(usize, usize)>,
+    store: Option<ethers_types::StorageDiff>,
+}
+
+#[derive(Debug)]
+struct VmFrame {
+    trace: ethers_types::VMTrace,
+    pending: Option<PendingOp>,
+}
+
+impl VmFrame {
+    fn complete_pending(&mut self, env: Option<&ExecutionState>, gas_left: i64) {
+        if let (Some(pending), Some(operation)) = (self.pending.take(), self.trace.ops.last_mut()) {
+            let (push, mem) = match env {
+                Some(env) => (
+                    (0..pushed_items(pending.op))
+                        .rev()
+                        .map(|i| utils::ethnum_u256_to_ethers(env.stack.get(i)))
+                        .collect(),
+                    pending.memory.and_then(|(off, len)| {
+                        Some(ethers_types::MemoryDiff {
+                            off,
+                            data: env.memory.get(off..off.checked_add(len)?)?.to_vec().into(),
+                        })
+                    }),
+                ),
+                None => (vec![], None),
+            };
+            operation.ex = Some(ethers_types::VMExecutedOperation {
+                used: gas_left as u64,
+                push,
+                mem,
+                store: pending.store,
+            });
+        }
+    }
+}
+
+/// Builds Parity-style VM traces, one per executed transaction.
+///
+/// The code of called contracts is not visible to tracers, so frames only record the
+/// address whose code runs in them; `into_transactions` fills the code in afterwards.
+#[derive(Debug, Default)]
+pub struct VmTracer {
+    transactions: Vec<ethers_types::VMTrace>,
+    frames: Vec<VmFrame>,
+    /// Address whose code runs in each frame, in the order the frames were entered.
+    /// Create frames run their input, which is known up front.
+    code_addresses: Vec<Option<Address>>,
+}
+
+impl VmTracer {
+    /// VM traces of every transaction executed so far, in execution order, with the code
+    /// of each call frame looked up through `code`.
+    pub fn into_transactions(
+        self,
+        mut code: impl FnMut(Address) -> anyhow::Result<Bytes>,
+    ) -> anyhow::Result<Vec<ethers_types::VMTrace>> {
+        fn fill_code(
+            trace: &mut ethers_types::VMTrace,
+            code_addresses: &mut std::vec::IntoIter<Option<Address>>,
+            code: &mut dyn FnMut(Address) -> anyhow::Result<Bytes>,
+        ) -> anyhow::Result<()> {
+            if let Some(Some(address)) = code_addresses.next() {
+                trace.code = code(address)?.into();
+            }
+            for operation in &mut trace.ops {
+                if let Some(sub) = &mut operation.sub {
+                    fill_code(sub, code_addresses, code)?;
+                }
+            }
+            Ok(())
+        }
+
+        let mut code_addresses = self.code_addresses.into_iter();
+        let mut transactions = self.transactions;
+        for trace in &mut transactions {
+            fill_code(trace, &mut code_addresses, &mut code)?;
+        }
+        Ok(transactions)
+    }
+}
+
+impl Tracer for VmTracer {
+    fn trace_instructions(&self) -> bool {
+        true
+    }
+
+    fn capture_start(
+        &mut self,
+        _depth: u16,
+        _sender: Address,
+        _recipient: Address,
+        _real_sender: Address,
+        code_address: Address,
+        call_type: MessageKind,
+        input: Bytes,
+        _gas: u64,
+        _value: U256,
+    ) {
+        let (code, code_address) = match call_type {
+            MessageKind::Create { .. } => (input, None),
+            MessageKind::Call { .. } => (Bytes::new(), Some(code_address)),
+        };
+        self.code_addresses.push(code_address);
+        self.frames.push(VmFrame {
+            trace: ethers_types::VMTrace {
+                code: code.into(),
+                ops: vec![],
+            },
+            pending: None,
+        });
+    }
+
+    fn capture_state(
+        &mut self,
+        env: &ExecutionState,
+        pc: usize,
+        op: OpCode,
+        cost: u64,
+        _depth: u16,
+    ) {
+        let frame = match self.frames.last_mut() {
+            Some(frame) => frame,
+            None => return,
+        };
+        frame.complete_pending(Some(env), env.gas_left);
+
+        frame.trace.ops.push(ethers_types::VMOperation {
+            pc,
+            cost,
+            ex: None,
+            sub: None,
+        });
+        frame.pending = Some(PendingOp {
+            op,
+            memory: written_memory(env, op),
+            store: (op == OpCode::SSTORE).then(|| ethers_types::StorageDiff {
+                key: utils::ethnum_u256_to_ethers(env.stack.get(0)),
+                val: utils::ethnum_u256_to_ethers(env.stack.get(1)),
+            }),
+        });
+    }
+
+    fn capture_end(&mut self, _depth: usize, _start_gas: u64, output: &Output) {
+        let mut frame = match self.frames.pop() {
+            Some(frame) => frame,
+            None => return,
+        };
+        // The last instruction halted the frame and pushed nothing.
+        frame.complete_pending(None, output.gas_left);
+
+        match self.frames.last_mut() {
+            Some(parent) => {
+                if let Some(operation) = parent.trace.ops.last_mut() {
+                    operation.sub = Some(frame.trace);
+                }
+            }
+            None => self.transactions.push(frame.trace),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use akula::execution::{
+        evm::{CallKind as InterpreterCallKind, InterpreterMessage},
+        tracer::CodeKind,
+    };
+
+    const SENDER: Address = H160([0x11; 20]);
+    const CONTRACT: Address = H160([0x22; 20]);
+    const CALLEE: Address = H160([0x33; 20]);
+
+    /// Execution state with `stack` on the stack, its first word on top.
+    fn state(stack: &[U256]) -> ExecutionState {
+        let mut state = ExecutionState::new(InterpreterMessage {
+            kind: InterpreterCallKind::Call,
+            is_static: false,
+            depth: 0,
+            gas: 100_000,
+            recipient: CONTRACT,
+            code_address: CONTRACT,
+            sender: SENDER,
+            input_data: Bytes::new(),
+            value: U256::ZERO,
+        });
+        for word in stack.iter().rev() {
+            state.stack.push(*word);
+        }
+        state
+    }
+
+    fn output(status_code: StatusCode, gas_left: i64) -> Output {
+        Output {
+            status_code,
+            gas_left,
+            output_data: Bytes::from_static(b"out"),
+            create_address: None,
+        }
+    }
+
+    fn call() -> MessageKind {
+        MessageKind::Call {
+            call_kind: CallKind::Call,
+            code_kind: CodeKind::Bytecode(None),
+        }
+    }
+
+    fn start(tracer: &mut dyn Tracer, depth: u16, sender: Address, recipient: Address) {
+        tracer.capture_start(
+            depth,
+            sender,
+            recipient,
+            sender,
+            recipient,
+            call(),
+            Bytes::new(),
+            50_000,
+            U256::ZERO,
+        );
+    }
+
+    fn word(address: Address) -> U256 {
+        U256::from_be_bytes(H256::from(address).0)
+    }
+
+    #[test]
+    fn access_list_records_slots_and_accessed_accounts() {
+        let mut tracer = AccessListTracer::new(&[], [SENDER, CONTRACT]);
+        start(&mut tracer, 0, SENDER, CONTRACT);
+        tracer.capture_state(&state(&[U256::new(7)]), 0, OpCode::SLOAD, 2100, 0);
+        tracer.capture_state(&state(&[word(SENDER)]), 1, OpCode::BALANCE, 100, 0);
+        tracer.capture_state(
+            &state(&[U256::new(1000), word(CALLEE)]),
+            2,
+            OpCode::STATICCALL,
+            2600,
+            0,
+        );
+        tracer.capture_end(0, 50_000, &output(StatusCode::Success, 0));
+
+        assert_eq!(
+            tracer.access_list(),
+            vec![
+                AccessListItem {
+                    address: CONTRACT,
+                    slots: vec![H256::from_low_u64_be(7)],
+                },
+                AccessListItem {
+                    address: CALLEE,
+                    slots: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parity_traces_nest_frames() {
+        let mut tracer = ParityTracer::default();
+        start(&mut tracer, 0, SENDER, CONTRACT);
+        start(&mut tracer, 1, CONTRACT, CALLEE);
+        tracer.capture_end(1, 20_000, &output(StatusCode::Success, 15_000));
+        tracer.capture_self_destruct(CONTRACT, SENDER, U256::new(5));
+        tracer.capture_end(0, 50_000, &output(StatusCode::Revert, 0));
+
+        assert_eq!(tracer.outputs(), &[Bytes::from_static(b"out")]);
+        let traces = tracer.into_transactions().pop().unwrap();
+        assert_eq!(
+            traces
+                .iter()
+                .map(|trace| (trace.trace_address.clone(), trace.subtraces))
+                .collect::<Vec<_>>(),
+            vec![(vec![], 2), (vec![0], 0), (vec![1], 0)]
+        );
+        assert_eq!(traces[0].error.as_deref(), Some("Reverted"));
+        assert!(matches!(
+            &traces[1].result,
+            Some(ethers_types::Res::Call(result)) if result.gas_used == 5_000.into()
+        ));
+        assert_eq!(traces[2].action_type, ethers_types::ActionType::Suicide);
+
+        let localized = localize_traces(
+            traces,
+            3,
+            H256::repeat_byte(1),
+            BlockNumber(9),
+            H256::zero(),
+        );
+        assert!(localized
+            .iter()
+            .all(|trace| trace.transaction_position == Some(3)
+                && trace.transaction_hash == Some(H256::repeat_byte(1))
+                && trace.block_number == 9));
+    }
+
+    #[test]
+    fn struct_logger_records_the_transaction_after_the_skipped_ones() {
+        let mut logger = StructLogger::skipping(StructLoggerConfig::default(), 1);
+
+        start(&mut logger, 0, SENDER, CONTRACT);
+        logger.capture_state(&state(&[]), 0, OpCode::STOP, 0, 0);
+        logger.capture_end(0, 50_000, &output(StatusCode::Success, 50_000));
+
+        start(&mut logger, 0, SENDER, CONTRACT);
+        logger.capture_state(&state(&[U256::new(1)]), 0, OpCode::SLOAD, 2100, 0);
+        logger.capture_state(&state(&[U256::new(42)]), 1, OpCode::POP, 2, 0);
+        logger.capture_end(0, 50_000, &output(StatusCode::Success, 40_000));
+
+        let (logs, output) = logger.into_parts();
+        assert_eq!(output, Bytes::from_static(b"out"));
+        assert_eq!(
+            logs.iter().map(|log| log.op.as_str()).collect::<Vec<_>>(),
+            vec!["SLOAD", "POP"]
+        );
+        assert_eq!(
+            logs[0].storage,
+            Some(BTreeMap::from([(
+                ethers_types::H256::from_low_u64_be(1),
+                ethers_types::H256::from_low_u64_be(42),
+            )]))
+        );
+        assert_eq!(logs[1].storage, None);
+    }
+
+    #[test]
+    fn struct_logger_drops_loads_that_failed() {
+        let mut logger = StructLogger::new(StructLoggerConfig::default());
+        start(&mut logger, 0, SENDER, CONTRACT);
+        logger.capture_state(&state(&[U256::new(1)]), 0, OpCode::SLOAD, 2100, 0);
+        logger.capture_end(0, 50_000, &output(StatusCode::OutOfGas, 0));
+
+        let (logs, _) = logger.into_parts();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].storage, None);
+        assert_eq!(logs[0].error.as_deref(), Some("out of gas"));
+    }
+
+    #[test]
+    fn state_diff_tracer_attributes_stores_to_their_context() {
+        let mut tracer = StateDiffTracer::default();
+        start(&mut tracer, 0, SENDER, CONTRACT);
+        start(&mut tracer, 1, CONTRACT, CALLEE);
+        tracer.capture_state(
+            &state(&[U256::new(3), U256::new(4)]),
+            0,
+            OpCode::SSTORE,
+            0,
+            1,
+        );
+        tracer.capture_end(1, 20_000, &output(StatusCode::Success, 0));
+        tracer.capture_self_destruct(CONTRACT, Address::repeat_byte(0x44), U256::ZERO);
+        tracer.capture_end(0, 50_000, &output(StatusCode::Success, 0));
+        start(&mut tracer, 0, SENDER, CALLEE);
+        tracer.capture_end(0, 50_000, &output(StatusCode::Success, 0));
+
+        let transactions = tracer.into_transactions();
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(
+            transactions[0].accounts,
+            BTreeSet::from([SENDER, CONTRACT, CALLEE, Address::repeat_byte(0x44)])
+        );
+        assert_eq!(
+            transactions[0].storage,
+            BTreeMap::from([(CALLEE, BTreeSet::from([H256::from_low_u64_be(3)]))])
+        );
+        assert_eq!(transactions[1].accounts, BTreeSet::from([SENDER, CALLEE]));
+    }
+
+    #[test]
+    fn written_memory_of_unpayable_ranges() {
+        assert_eq!(
+            written_memory(&state(&[U256::new(64)]), OpCode::MSTORE),
+            Some((64, 32))
+        );
+        assert_eq!(
+            written_memory(
+                &state(&[U256::new(0), U256::new(0), U256::ZERO]),
+                OpCode::CALLDATACOPY
+            ),
+            None
+        );
+        assert_eq!(written_memory(&state(&[U256::MAX]), OpCode::MSTORE), None);
+        assert_eq!(
+            written_memory(
+                &state(&[U256::new(0), U256::new(0), U256::MAX]),
+                OpCode::CODECOPY
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn vm_tracer_completes_instructions_on_the_next_step() {
+        let mut tracer = VmTracer::default();
+        start(&mut tracer, 0, SENDER, CONTRACT);
+        tracer.capture_state(&state(&[]), 0, OpCode::PUSH1, 3, 0);
+        // The store's memory is missing from the synthetic state, so it can't be read.
+        tracer.capture_state(
+            &state(&[U256::new(0), U256::new(9)]),
+            2,
+            OpCode::MSTORE,
+            6,
+            0,
+        );
+        tracer.capture_state(&state(&[U256::MAX, U256::new(9)]), 3, OpCode::MSTORE, 6, 0);
+        tracer.capture_end(0, 50_000, &output(StatusCode::OutOfGas, 0));
+
+        let traces = tracer
+            .into_transactions(|_| Ok(Bytes::from_static(b"code")))
+            .unwrap();
+        let ops = &traces[0].ops;
+        assert_eq!(traces[0].code, Bytes::from_static(b"code").into());
+        assert_eq!(ops.len(), 3);
+        assert_eq!(
+            ops[0].ex.as_ref().unwrap().push,
+            vec![ethers_types::U256::zero()]
+        );
+        assert!(ops[1].ex.as_ref().unwrap().mem.is_none());
+        assert!(ops[2].ex.as_ref().unwrap().push.is_empty());
+    }
+}
@@ -1,6 +1,20 @@
-use std::collections::HashSet;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+};
 
-use crate::middleware::AkulaMiddlewareError;
+use crate::{
+    db_wrapper::{
+        self, AccountChange, AccountProof, BundleTransactionResult, FeeHistory, LogFilter,
+        LogFilterBlocks, TraceTypes,
+    },
+    executor::InvalidTransaction,
+    middleware::{
+        AkulaMiddlewareError, BlockOverride, DumpAccount, ExecutionError, RevertReason,
+        SimulatedTransaction, StateOverride, StorageRange,
+    },
+    tracer::{self, StructLoggerConfig},
+};
 use akula::{
     binutil::AkulaDataDir,
     kv::{mdbx::*, MdbxWithDirHandle},
@@ -22,24 +36,48 @@ pub fn open_database(db_path: AkulaDataDir) -> anyhow::Result<MdbxWithDirHandle<
 
 pub fn ethers_block_id_to_akula(block_id: ethers_types::BlockId) -> jsonrpc::BlockId {
     match block_id {
-        ethers_types::BlockId::Number(number) => match number {
-            ethers_types::BlockNumber::Latest => {
-                jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest)
-            }
-            ethers_types::BlockNumber::Earliest => {
-                jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Earliest)
-            }
-            ethers_types::BlockNumber::Pending => {
-                jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest)
-            }
-            ethers_types::BlockNumber::Number(n) => {
-                jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Number(n))
-            }
-        },
+        ethers_types::BlockId::Number(number) => {
+            jsonrpc::BlockId::Number(ethers_block_number_to_akula(number))
+        }
         ethers_types::BlockId::Hash(hash) => jsonrpc::BlockId::Hash(hash),
     }
 }
 
+pub fn ethers_block_number_to_akula(number: ethers_types::BlockNumber) -> jsonrpc::BlockNumber {
+    match number {
+        ethers_types::BlockNumber::Latest => jsonrpc::BlockNumber::Latest,
+        ethers_types::BlockNumber::Earliest => jsonrpc::BlockNumber::Earliest,
+        // Only the middleware can serve the pending block; see `settled_block_id`.
+        ethers_types::BlockNumber::Pending => jsonrpc::BlockNumber::Pending,
+        ethers_types::BlockNumber::Number(n) => jsonrpc::BlockNumber::Number(n),
+    }
+}
+
+/// Refuses the pending block for queries answered from the database alone, which would
+/// otherwise silently see the latest block.
+pub fn settled_block_number<M: Middleware>(
+    number: jsonrpc::BlockNumber,
+) -> Result<jsonrpc::BlockNumber, AkulaMiddlewareError<M>> {
+    match number {
+        jsonrpc::BlockNumber::Pending => Err(AkulaMiddlewareError::Unsupported(
+            "this query against the pending block".into(),
+        )),
+        number => Ok(number),
+    }
+}
+
+/// Refuses the pending block like `settled_block_number`.
+pub fn settled_block_id<M: Middleware>(
+    block_id: jsonrpc::BlockId,
+) -> Result<jsonrpc::BlockId, AkulaMiddlewareError<M>> {
+    match block_id {
+        jsonrpc::BlockId::Number(number) => {
+            Ok(jsonrpc::BlockId::Number(settled_block_number(number)?))
+        }
+        block_id => Ok(block_id),
+    }
+}
+
 pub fn ethers_typed_tx_to_message_call<M: Middleware>(
     typed_transaction: &ethers_types::transaction::eip2718::TypedTransaction,
 ) -> Result<jsonrpc::MessageCall, AkulaMiddlewareError<M>> {
@@ -118,6 +156,145 @@ pub fn ethers_typed_tx_to_message_call<M: Middleware>(
     }
 }
 
+pub fn txpool_tx_to_message_call(
+    sender: ethers_types::Address,
+    tx: &ethers_types::TxpoolTransaction,
+) -> jsonrpc::MessageCall {
+    jsonrpc::MessageCall::Legacy {
+        from: Some(sender),
+        to: tx.to,
+        gas: tx.gas.map(|gas| gas.as_u64().into()),
+        gas_price: tx.gas_price.as_ref().map(ethers_u256_to_ethnum),
+        value: Some(ethers_u256_to_ethnum(&tx.value)),
+        data: Some(jsonrpc::Bytes::from(tx.input.0.clone())),
+    }
+}
+
+/// Orders the txpool's pending transactions the way a block producer includes them: each
+/// sender's in nonce order, and among the next transactions of all senders, the one with
+/// the highest gas price first.
+pub fn order_pending_transactions(
+    pending: BTreeMap<ethers_types::Address, BTreeMap<String, ethers_types::TxpoolTransaction>>,
+) -> Vec<(ethers_types::Address, ethers_types::TxpoolTransaction)> {
+    let mut queues = pending
+        .into_iter()
+        .map(|(sender, txs)| {
+            let mut txs = txs.into_values().collect::<Vec<_>>();
+            txs.sort_by_key(|tx| tx.nonce);
+            (sender, txs.into_iter().peekable())
+        })
+        .collect::<BTreeMap<_, _>>();
+    // Ties go to the lower address, so that the order doesn't depend on the txpool's.
+    let mut heads = queues
+        .iter_mut()
+        .filter_map(|(sender, txs)| Some((txs.peek()?.gas_price, Reverse(*sender))))
+        .collect::<BinaryHeap<_>>();
+
+    let mut ordered = Vec::new();
+    while let Some((_, Reverse(sender))) = heads.pop() {
+        let txs = queues.get_mut(&sender).expect("every head has a queue");
+        ordered.extend(txs.next().map(|tx| (sender, tx)));
+        if let Some(next) = txs.peek() {
+            heads.push((next.gas_price, Reverse(sender)));
+        }
+    }
+    ordered
+}
+
+pub fn ethers_filter_to_log_filter(filter: &ethers_types::Filter) -> LogFilter {
+    let blocks = match &filter.block_option {
+        ethers_types::FilterBlockOption::Range {
+            from_block,
+            to_block,
+        } => LogFilterBlocks::Range {
+            from: ethers_block_number_to_akula(
+                from_block.unwrap_or(ethers_types::BlockNumber::Latest),
+            ),
+            to: ethers_block_number_to_akula(to_block.unwrap_or(ethers_types::BlockNumber::Latest)),
+        },
+        ethers_types::FilterBlockOption::AtBlockHash(hash) => LogFilterBlocks::Hash(*hash),
+    };
+    let addresses = filter.address.as_ref().map(|address| match address {
+        ethers_types::ValueOrArray::Value(address) => vec![*address],
+        ethers_types::ValueOrArray::Array(addresses) => addresses.clone(),
+    });
+    // A wildcard anywhere in a topic position turns the whole position into a wildcard.
+    let topics = filter.topics.clone().map(|topic| match topic? {
+        ethers_types::ValueOrArray::Value(topic) => topic.map(|topic| vec![topic]),
+        ethers_types::ValueOrArray::Array(topics) => topics.into_iter().collect(),
+    });
+
+    LogFilter {
+        blocks,
+        addresses,
+        topics,
+    }
+}
+
+pub fn ethers_state_override_to_akula(state_override: &StateOverride) -> db_wrapper::StateOverride {
+    let storage = |storage: &HashMap<ethers_types::H256, ethers_types::H256>| {
+        storage
+            .iter()
+            .map(|(location, value)| (*location, akula::models::U256::from_be_bytes(value.0)))
+            .collect()
+    };
+
+    state_override
+        .iter()
+        .map(|(address, account_override)| {
+            (
+                *address,
+                db_wrapper::AccountOverride {
+                    balance: account_override.balance.as_ref().map(ethers_u256_to_ethnum),
+                    nonce: account_override.nonce.map(|nonce| nonce.as_u64()),
+                    code: account_override.code.as_ref().map(|code| code.0.clone()),
+                    state: account_override.state.as_ref().map(storage),
+                    state_diff: account_override.state_diff.as_ref().map(storage),
+                },
+            )
+        })
+        .collect()
+}
+
+pub fn ethers_block_override_to_akula(block_override: &BlockOverride) -> db_wrapper::BlockOverride {
+    db_wrapper::BlockOverride {
+        number: block_override
+            .number
+            .map(|number| akula::models::BlockNumber(number.as_u64())),
+        timestamp: block_override.timestamp.map(|timestamp| timestamp.as_u64()),
+        base_fee_per_gas: block_override
+            .base_fee_per_gas
+            .as_ref()
+            .map(ethers_u256_to_ethnum),
+        coinbase: block_override.coinbase,
+        gas_limit: block_override.gas_limit.map(|gas_limit| gas_limit.as_u64()),
+        difficulty: block_override
+            .difficulty
+            .as_ref()
+            .map(ethers_u256_to_ethnum),
+        prevrandao: block_override.prevrandao,
+    }
+}
+
+pub fn ethers_trace_types_to_akula(trace_types: &[ethers_types::TraceType]) -> TraceTypes {
+    TraceTypes {
+        trace: trace_types.contains(&ethers_types::TraceType::Trace),
+        vm_trace: trace_types.contains(&ethers_types::TraceType::VmTrace),
+        state_diff: trace_types.contains(&ethers_types::TraceType::StateDiff),
+    }
+}
+
+/// Memory capture is opt-in, as in geth.
+pub fn ethers_tracing_options_to_struct_logger_config(
+    options: &ethers_types::GethDebugTracingOptions,
+) -> StructLoggerConfig {
+    StructLoggerConfig {
+        disable_memory: !options.enable_memory.unwrap_or(false),
+        disable_stack: options.disable_stack.unwrap_or(false),
+        disable_storage: options.disable_storage.unwrap_or(false),
+    }
+}
+
 #[inline]
 pub fn ethers_u256_to_ethnum(n: &ethers_types::U256) -> akula::models::U256 {
     let mut bytes: [u8; 32] = [0; 32];
@@ -128,7 +305,252 @@ pub fn ethers_u256_to_ethnum(n: &ethers_types::U256) -> akula::models::U256 {
 #[inline]
 pub fn ethnum_u256_to_ethers(n: &akula::models::U256) -> ethers_types::U256 {
     let bytes = n.to_le_bytes();
-    ethers_types::U256::from_big_endian(&bytes)
+    ethers_types::U256::from_little_endian(&bytes)
+}
+
+pub fn fee_history_to_ethers(history: FeeHistory) -> ethers_types::FeeHistory {
+    ethers_types::FeeHistory {
+        base_fee_per_gas: history
+            .base_fee_per_gas
+            .iter()
+            .map(ethnum_u256_to_ethers)
+            .collect(),
+        gas_used_ratio: history.gas_used_ratio,
+        oldest_block: ethers_types::U256::from(history.oldest_block.0),
+        reward: history
+            .reward
+            .iter()
+            .map(|reward| reward.iter().map(ethnum_u256_to_ethers).collect())
+            .collect(),
+    }
+}
+
+pub fn access_list_to_ethers(
+    access_list: Vec<akula::models::AccessListItem>,
+) -> ethers_types::transaction::eip2930::AccessList {
+    ethers_types::transaction::eip2930::AccessList(
+        access_list
+            .into_iter()
+            .map(|item| ethers_types::transaction::eip2930::AccessListItem {
+                address: item.address,
+                storage_keys: item.slots,
+            })
+            .collect(),
+    )
+}
+
+pub fn account_proof_to_ethers(proof: AccountProof) -> ethers_types::EIP1186ProofResponse {
+    ethers_types::EIP1186ProofResponse {
+        address: proof.address,
+        balance: ethnum_u256_to_ethers(&proof.balance),
+        code_hash: proof.code_hash,
+        nonce: ethers_types::U64::from(proof.nonce),
+        storage_hash: proof.storage_hash,
+        account_proof: proof
+            .account_proof
+            .into_iter()
+            .map(ethers_types::Bytes::from)
+            .collect(),
+        storage_proof: proof
+            .storage_proof
+            .into_iter()
+            .map(|storage_proof| ethers_types::StorageProof {
+                key: storage_proof.key,
+                proof: storage_proof
+                    .proof
+                    .into_iter()
+                    .map(ethers_types::Bytes::from)
+                    .collect(),
+                value: ethnum_u256_to_ethers(&storage_proof.value),
+            })
+            .collect(),
+    }
+}
+
+pub fn dump_account_to_ethers(account: db_wrapper::DumpAccount) -> DumpAccount {
+    DumpAccount {
+        address: account.address,
+        balance: ethnum_u256_to_ethers(&account.balance),
+        nonce: account.nonce.into(),
+        code_hash: account.code_hash,
+        code: account.code.into(),
+        storage: account.storage.map(|storage| {
+            storage
+                .into_iter()
+                .map(|(location, value)| (location, ethers_types::H256(value.to_be_bytes())))
+                .collect()
+        }),
+    }
+}
+
+pub fn storage_range_to_ethers(range: db_wrapper::StorageRange) -> StorageRange {
+    StorageRange {
+        storage: range
+            .storage
+            .into_iter()
+            .map(|(location, value)| (location, ethers_types::H256(value.to_be_bytes())))
+            .collect(),
+        next_key: range.next_key,
+    }
+}
+
+fn diff<T: PartialEq>(before: Option<T>, after: Option<T>) -> ethers_types::Diff<T> {
+    match (before, after) {
+        (None, Some(after)) => ethers_types::Diff::Born(after),
+        (Some(before), None) => ethers_types::Diff::Died(before),
+        (Some(before), Some(after)) if before != after => {
+            ethers_types::Diff::Changed(ethers_types::ChangedType {
+                from: before,
+                to: after,
+            })
+        }
+        _ => ethers_types::Diff::Same,
+    }
+}
+
+/// Parity-style state diff, leaving out accounts that did not change.
+pub fn account_changes_to_state_diff(
+    changes: BTreeMap<ethers_types::Address, AccountChange>,
+) -> ethers_types::StateDiff {
+    ethers_types::StateDiff(
+        changes
+            .into_iter()
+            .filter_map(|(address, change)| {
+                let exists = (change.before.is_some(), change.after.is_some());
+                // Slots of an account that is born or dies are born or die along with it.
+                let slot_value = |value: akula::models::U256, account_exists: bool| {
+                    account_exists.then(|| ethers_types::H256(value.to_be_bytes()))
+                };
+                let storage = change
+                    .storage
+                    .into_iter()
+                    .map(|(slot, (before, after))| {
+                        (
+                            slot,
+                            diff(slot_value(before, exists.0), slot_value(after, exists.1)),
+                        )
+                    })
+                    .filter(|(_, diff)| !matches!(diff, ethers_types::Diff::Same))
+                    .collect::<BTreeMap<_, _>>();
+
+                let account_diff = ethers_types::AccountDiff {
+                    balance: diff(
+                        change
+                            .before
+                            .as_ref()
+                            .map(|account| ethnum_u256_to_ethers(&account.balance)),
+                        change
+                            .after
+                            .as_ref()
+                            .map(|account| ethnum_u256_to_ethers(&account.balance)),
+                    ),
+                    nonce: diff(
+                        change
+                            .before
+                            .as_ref()
+                            .map(|account| ethers_types::U256::from(account.nonce)),
+                        change
+                            .after
+                            .as_ref()
+                            .map(|account| ethers_types::U256::from(account.nonce)),
+                    ),
+                    code: diff(
+                        change
+                            .before
+                            .map(|account| ethers_types::Bytes::from(account.code)),
+                        change
+                            .after
+                            .map(|account| ethers_types::Bytes::from(account.code)),
+                    ),
+                    storage,
+                };
+
+                let unchanged = matches!(account_diff.balance, ethers_types::Diff::Same)
+                    && matches!(account_diff.nonce, ethers_types::Diff::Same)
+                    && matches!(account_diff.code, ethers_types::Diff::Same)
+                    && account_diff.storage.is_empty();
+                (!unchanged).then_some((address, account_diff))
+            })
+            .collect(),
+    )
+}
+
+/// Decodes the `Error(string)` and `Panic(uint256)` payloads Solidity reverts with.
+pub fn decode_revert_reason(data: &[u8]) -> Option<RevertReason> {
+    const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+    const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+
+    if data.len() < 4 {
+        return None;
+    }
+    let (selector, payload) = data.split_at(4);
+    let decode = |param| ethers::abi::decode(&[param], payload).ok()?.pop();
+
+    if selector == ERROR_SELECTOR {
+        decode(ethers::abi::ParamType::String)?
+            .into_string()
+            .map(RevertReason::Error)
+    } else if selector == PANIC_SELECTOR {
+        decode(ethers::abi::ParamType::Uint(256))?
+            .into_uint()
+            .map(RevertReason::Panic)
+    } else {
+        None
+    }
+}
+
+/// Error of an execution that ended with `status_code`, if it did not succeed.
+pub fn execution_error(
+    status_code: akula::execution::evm::StatusCode,
+    output: &[u8],
+) -> Option<ExecutionError> {
+    use akula::execution::evm::StatusCode;
+
+    match status_code {
+        StatusCode::Success => None,
+        StatusCode::Revert => Some(ExecutionError::Reverted {
+            data: ethers_types::Bytes::from(output.to_vec()),
+            reason: decode_revert_reason(output),
+        }),
+        StatusCode::OutOfGas => Some(ExecutionError::OutOfGas),
+        StatusCode::UndefinedInstruction => Some(ExecutionError::InvalidOpcode),
+        // 0xFE is a deliberate abort, reported by geth as "invalid opcode: INVALID".
+        other => Some(ExecutionError::Halted(tracer::geth_error(&other))),
+    }
+}
+
+pub fn bundle_result_to_ethers(
+    result: Result<BundleTransactionResult, InvalidTransaction>,
+) -> SimulatedTransaction {
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            return SimulatedTransaction {
+                success: false,
+                output: ethers_types::Bytes::default(),
+                gas_used: ethers_types::U256::zero(),
+                logs: vec![],
+                error: Some(ExecutionError::Invalid(e.to_string())),
+            }
+        }
+    };
+
+    SimulatedTransaction {
+        success: result.status_code == akula::execution::evm::StatusCode::Success,
+        error: execution_error(result.status_code, &result.output),
+        output: ethers_types::Bytes::from(result.output),
+        gas_used: ethers_types::U256::from(result.gas_used),
+        logs: result
+            .logs
+            .into_iter()
+            .map(|log| ethers_types::Log {
+                address: log.address,
+                topics: log.topics,
+                data: ethers_types::Bytes::from(log.data),
+                ..Default::default()
+            })
+            .collect(),
+    }
 }
 
 pub fn jsonrpc_block_with_txs_to_ethers(
@@ -271,3 +693,154 @@ pub fn jsonrpc_log_to_ethers(log: &jsonrpc::TransactionLog) -> ethers_types::Log
         removed: None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use akula::execution::evm::StatusCode;
+    use ethers::abi::{self, Token};
+
+    fn revert_data(selector: [u8; 4], token: Token) -> Vec<u8> {
+        [&selector[..], &abi::encode(&[token])].concat()
+    }
+
+    fn txpool_tx(from: u8, nonce: u64, gas_price: u64) -> ethers_types::TxpoolTransaction {
+        serde_json::from_value(serde_json::json!({
+            "blockHash": null,
+            "blockNumber": null,
+            "from": ethers_types::Address::repeat_byte(from),
+            "gas": "0x5208",
+            "gasPrice": ethers_types::U256::from(gas_price),
+            "hash": ethers_types::H256::from_low_u64_be(u64::from(from) << 32 | nonce),
+            "input": "0x",
+            "nonce": ethers_types::U256::from(nonce),
+            "to": ethers_types::Address::zero(),
+            "transactionIndex": null,
+            "value": "0x0",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pending_transactions_by_nonce_then_price() {
+        let mut pending = BTreeMap::new();
+        for (from, nonce, gas_price) in [(1, 5, 10), (1, 4, 1), (2, 0, 5), (2, 1, 20), (3, 9, 5)] {
+            pending
+                .entry(ethers_types::Address::repeat_byte(from))
+                .or_insert_with(BTreeMap::new)
+                .insert(nonce.to_string(), txpool_tx(from, nonce, gas_price));
+        }
+
+        let ordered = order_pending_transactions(pending)
+            .into_iter()
+            .map(|(sender, tx)| (sender.0[0], tx.nonce.as_u64()))
+            .collect::<Vec<_>>();
+        // Sender 1's cheap transaction holds back its expensive one, while sender 2's
+        // expensive one follows its first as soon as that is included.
+        assert_eq!(ordered, vec![(2, 0), (2, 1), (3, 9), (1, 4), (1, 5)]);
+    }
+
+    #[test]
+    fn pending_block_only_for_middleware_queries() {
+        type M = ethers::providers::Provider<ethers::providers::MockProvider>;
+
+        let pending = ethers_block_id_to_akula(ethers_types::BlockId::Number(
+            ethers_types::BlockNumber::Pending,
+        ));
+        assert!(matches!(
+            pending,
+            jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Pending)
+        ));
+        assert!(matches!(
+            settled_block_id::<M>(pending),
+            Err(AkulaMiddlewareError::Unsupported(_))
+        ));
+        assert!(matches!(
+            settled_block_id::<M>(jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest)),
+            Ok(jsonrpc::BlockId::Number(jsonrpc::BlockNumber::Latest))
+        ));
+    }
+
+    #[test]
+    fn decode_error_and_panic_reasons() {
+        let error = revert_data(
+            [0x08, 0xc3, 0x79, 0xa0],
+            Token::String("not enough funds".to_string()),
+        );
+        assert_eq!(
+            decode_revert_reason(&error),
+            Some(RevertReason::Error("not enough funds".to_string()))
+        );
+
+        let panic = revert_data(
+            [0x4e, 0x48, 0x7b, 0x71],
+            Token::Uint(ethers_types::U256::from(0x11)),
+        );
+        assert_eq!(
+            decode_revert_reason(&panic),
+            Some(RevertReason::Panic(ethers_types::U256::from(0x11)))
+        );
+        assert_eq!(
+            RevertReason::Panic(ethers_types::U256::from(0x11)).to_string(),
+            "panic code 0x11"
+        );
+    }
+
+    #[test]
+    fn undecodable_revert_reasons() {
+        let error = revert_data(
+            [0x08, 0xc3, 0x79, 0xa0],
+            Token::String("not enough funds".to_string()),
+        );
+        assert_eq!(decode_revert_reason(&error[..40]), None);
+        assert_eq!(decode_revert_reason(&error[..4]), None);
+        assert_eq!(decode_revert_reason(&[0x08, 0xc3, 0x79]), None);
+        assert_eq!(decode_revert_reason(&[]), None);
+
+        let custom = revert_data(
+            [0xde, 0xad, 0xbe, 0xef],
+            Token::Uint(ethers_types::U256::one()),
+        );
+        assert_eq!(decode_revert_reason(&custom), None);
+    }
+
+    #[test]
+    fn execution_errors_of_status_codes() {
+        assert!(execution_error(StatusCode::Success, &[]).is_none());
+
+        let error = revert_data(
+            [0x08, 0xc3, 0x79, 0xa0],
+            Token::String("not enough funds".to_string()),
+        );
+        let reverted = execution_error(StatusCode::Revert, &error).unwrap();
+        assert_eq!(reverted.to_string(), "execution reverted: not enough funds");
+        assert!(matches!(
+            reverted,
+            ExecutionError::Reverted { data, reason: Some(RevertReason::Error(_)) }
+                if data.to_vec() == error
+        ));
+        assert_eq!(
+            execution_error(StatusCode::Revert, &[])
+                .unwrap()
+                .to_string(),
+            "execution reverted"
+        );
+
+        assert!(matches!(
+            execution_error(StatusCode::OutOfGas, &[]),
+            Some(ExecutionError::OutOfGas)
+        ));
+        assert!(matches!(
+            execution_error(StatusCode::UndefinedInstruction, &[]),
+            Some(ExecutionError::InvalidOpcode)
+        ));
+        assert!(matches!(
+            execution_error(StatusCode::InvalidInstruction, &[]),
+            Some(ExecutionError::Halted(message)) if message == "invalid opcode: INVALID"
+        ));
+        assert!(matches!(
+            execution_error(StatusCode::StackOverflow, &[]),
+            Some(ExecutionError::Halted(message)) if message == "stack limit reached"
+        ));
+    }
+}